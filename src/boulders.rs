@@ -0,0 +1,267 @@
+use super::ocad;
+use super::detection::Detection;
+use super::las::PointDataRecord;
+use crate::geometry::{Point3D,PointConverter};
+use super::dtm::{DigitalTerrainModel,Terrain,Z_NORMAL};
+use super::Sweref;
+use super::map_settings::MapSettings;
+use super::raster::connected_cells;
+use std::collections::{HashMap,HashSet,VecDeque};
+use colored::*;
+
+// Boulders are small, steep bumps of 1-3 m. They show up either as a local maximum in the
+// ground model, or as low points hovering over a hole in the ground points, where the
+// classification has not counted the boulder as ground.
+const MIN_BOULDER_HEIGHT: f64 = 1.0f64;
+const MAX_BOULDER_HEIGHT: f64 = 3.0f64;
+const LARGE_BOULDER_HEIGHT: f64 = 2.0f64;
+
+// Footprint radius for a boulder, and the width of the ring around it that is used as base.
+// Both are widened for sparse point clouds, in units of the average point spacing.
+const MAX_BOULDER_RADIUS: f64 = 1.5f64;
+const BASE_RING_WIDTH: f64 = 1.0f64;
+const SPACINGS_PER_RADIUS: f64 = 2.0f64;
+
+const MAX_ZNORMAL_FOR_BOULDER: f64 = 0.7f64;
+const MIN_SLOPE_TO_BASE: f64 = 0.8f64;
+// A bump on a slope is not a boulder.
+const MAX_BASE_VARIATION: f64 = 0.5f64;
+// Fraction of the expected number of points in the base ring.
+const MIN_BASE_COVERAGE: f64 = 0.3f64;
+
+const LOW_POINT_CLASSES: [u8;2] = [1u8, 3u8];
+const LOW_POINT_CELL_SIZE: f64 = 1.0f64;
+// Fraction of the expected number of ground points in a cell.
+const MIN_LOW_POINT_COVERAGE: f64 = 0.5f64;
+
+// Boulders closer than this are part of the same boulder field, if there are enough of them.
+const BOULDER_FIELD_DISTANCE: f64 = 6.0f64;
+const MIN_BOULDERS_IN_FIELD: usize = 5;
+// A boulder field symbol is placed on its members, no closer to the others than this at 1:15000.
+const MIN_FIELD_SYMBOL_SPACING: f64 = 8.0f64;
+
+const BOULDER: i32 = 204000;
+const LARGE_BOULDER: i32 = 205000;
+const BOULDER_FIELD: i32 = 208000;
+
+struct Boulder {
+    position: Point3D,
+    height: f64,
+    triangle: usize,
+}
+
+fn median(values: &mut Vec<f64>) -> f64 {
+    values.sort_by(|a,b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values[values.len() / 2]
+}
+
+fn boulders_in_dtm(dtm: &DigitalTerrainModel, spacing: f64, density: f64) -> Vec<Boulder> {
+    let normals = dtm.normals();
+    let z_limits = dtm.z_limits();
+    let (neighbours, triangles) = dtm.vertex_neighbours();
+
+    let inner = f64::max(MAX_BOULDER_RADIUS, SPACINGS_PER_RADIUS*spacing);
+    let outer = inner + f64::max(BASE_RING_WIDTH, SPACINGS_PER_RADIUS*spacing);
+    let expected_base_points = std::f64::consts::PI * (outer*outer - inner*inner) * density;
+
+    (0..dtm.points.len()).filter_map(|v| {
+        let top = dtm.points[v];
+        let around = &triangles[v];
+        if around.len() == 0 ||
+            around.iter().any(|t| dtm.exterior[*t] || dtm.terrain[*t] != Terrain::Unclassified) ||
+            neighbours[v].iter().any(|n| dtm.points[*n].z >= top.z) { return None }
+
+        let steepness = around.iter().map(|t| normals[*t][Z_NORMAL]).sum::<f64>() / (around.len() as f64);
+        let rise = around.iter().map(|t| z_limits[*t].1 - z_limits[*t].0).fold(0f64, f64::max);
+        if steepness > MAX_ZNORMAL_FOR_BOULDER || rise < MIN_BOULDER_HEIGHT * 0.5 { return None }
+
+        // Walk outwards. Everything within the footprint must be lower than the top, and the
+        // ring outside it is the ground the boulder rests on.
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        let mut base: Vec<(f64,f64)> = Vec::new();
+        visited.insert(v);
+        queue.push_back(v);
+        while let Some(p) = queue.pop_front() {
+            for n in neighbours[p].iter() {
+                if !visited.insert(*n) { continue }
+                let q = dtm.points[*n];
+                let d = top.distance_2d_to(&q);
+                if d >= outer { continue }
+                if d < inner {
+                    if q.z > top.z { return None }
+                } else {
+                    base.push((d, q.z));
+                }
+                queue.push_back(*n);
+            }
+        }
+        if (base.len() as f64) < f64::max(3f64, MIN_BASE_COVERAGE * expected_base_points) { return None }
+
+        let mut zs: Vec<f64> = base.iter().map(|b| b.1).collect();
+        let base_z = median(&mut zs);
+        let variation = zs[zs.len() * 9 / 10] - zs[zs.len() / 10];
+        let height = top.z - base_z;
+        let mean_distance = base.iter().map(|b| b.0).sum::<f64>() / (base.len() as f64);
+
+        if height >= MIN_BOULDER_HEIGHT && height <= MAX_BOULDER_HEIGHT &&
+            variation < MAX_BASE_VARIATION * height &&
+            height / mean_distance > MIN_SLOPE_TO_BASE {
+            Some(Boulder { position: top, height, triangle: around[0] })
+        } else {
+            None
+        }
+    }).collect()
+}
+
+fn boulders_in_low_points(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, spacing: f64, density: f64) -> Vec<Boulder> {
+
+    let radius = f64::max(MAX_BOULDER_RADIUS, SPACINGS_PER_RADIUS*spacing);
+    let min_points = f64::max(3f64, MIN_LOW_POINT_COVERAGE * density * LOW_POINT_CELL_SIZE * LOW_POINT_CELL_SIZE) as usize;
+
+    // Low points between 1 and 3 m above the ground, binned into cells.
+    let mut cells: HashMap<(i64,i64),Vec<(Point3D,f64)>> = HashMap::new();
+    let mut hint = 0usize;
+    for record in records.iter().filter(|r| LOW_POINT_CLASSES.contains(&r.classification())) {
+        let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+        if !dtm.bounds.contains_2d(&p) { continue }
+        let triangle = match dtm.triangle_containing_point(&p, hint) {
+            Some(t) => t,
+            None => continue,
+        };
+        hint = triangle;
+        if dtm.exterior[triangle] || dtm.terrain[triangle] != Terrain::Unclassified { continue }
+        let height = p.z - dtm.z_coordinate_in_triangle(&p, triangle);
+        if height < MIN_BOULDER_HEIGHT * 0.5 || height > MAX_BOULDER_HEIGHT { continue }
+        let cell = ((p.x / LOW_POINT_CELL_SIZE).floor() as i64, (p.y / LOW_POINT_CELL_SIZE).floor() as i64);
+        cells.entry(cell).or_insert(Vec::new()).push((p, height));
+    }
+
+    // Connected groups of well covered cells.
+    let covered: HashSet<(i64,i64)> = cells.iter()
+        .filter(|(_, points)| points.len() >= min_points)
+        .map(|(c, _)| *c)
+        .collect();
+    let mut boulders = Vec::new();
+    let mut hint = 0usize;
+    for group in connected_cells(&covered).iter() {
+        let points: Vec<&(Point3D,f64)> = group.iter().flat_map(|c| cells[c].iter()).collect();
+        let n = points.len() as f64;
+        let centre = Point3D {
+            x: points.iter().map(|p| p.0.x).sum::<f64>() / n,
+            y: points.iter().map(|p| p.0.y).sum::<f64>() / n,
+            z: points.iter().map(|p| p.0.z).sum::<f64>() / n,
+        };
+        let extent = points.iter().map(|p| centre.distance_2d_to(&p.0)).fold(0f64, f64::max);
+        if extent > radius { continue }
+
+        // Vegetation lets ground points through. A boulder leaves a hole in the ground model:
+        // no ground point inside its footprint.
+        let triangle = match dtm.triangle_containing_point(&centre, hint) {
+            Some(t) => t,
+            None => continue,
+        };
+        hint = triangle;
+        if (0..3).any(|i| dtm.points[dtm.vertices[triangle*3 + i]].distance_2d_to(&centre) < extent * 0.5) { continue }
+
+        let (top, height) = points.iter().fold((centre, 0f64), |m, p| if p.1 > m.1 { (p.0, p.1) } else { m });
+        if height >= MIN_BOULDER_HEIGHT {
+            boulders.push(Boulder { position: top, height, triangle });
+        }
+    }
+    boulders
+}
+
+pub fn detect_boulders(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, settings: &MapSettings,
+    verbose: bool) -> Vec<Detection> {
+
+    let module = "BOULDER".black();
+    let area = (dtm.bounds.upper.x - dtm.bounds.lower.x) * (dtm.bounds.upper.y - dtm.bounds.lower.y);
+    let density = (dtm.points.len() as f64) / area;
+    let spacing = 1f64 / f64::sqrt(density);
+    if verbose {
+        println!("[{}] Detecting boulders, {:.1} ground points per m².", &module, density);
+    }
+
+    let mut boulders = boulders_in_dtm(dtm, spacing, density);
+    let from_dtm = boulders.len();
+
+    // The same boulder may be found in both.
+    let radius = f64::max(MAX_BOULDER_RADIUS, SPACINGS_PER_RADIUS*spacing);
+    for b in boulders_in_low_points(records, point_converter, dtm, spacing, density).into_iter() {
+        if !boulders.iter().any(|o| o.position.distance_2d_to(&b.position) < radius) {
+            boulders.push(b);
+        }
+    }
+
+    // Single-linkage grouping into boulder fields.
+    let mut field_of_boulder: Vec<Option<usize>> = vec![None; boulders.len()];
+    let mut fields: Vec<Vec<usize>> = Vec::new();
+    for i in 0..boulders.len() {
+        if field_of_boulder[i].is_some() { continue }
+        let f = fields.len();
+        let mut members = vec![i];
+        field_of_boulder[i] = Some(f);
+        let mut k = 0;
+        while k < members.len() {
+            let p = boulders[members[k]].position;
+            for j in 0..boulders.len() {
+                if field_of_boulder[j].is_none() && boulders[j].position.distance_2d_to(&p) < BOULDER_FIELD_DISTANCE {
+                    field_of_boulder[j] = Some(f);
+                    members.push(j);
+                }
+            }
+            k = k + 1;
+        }
+        fields.push(members);
+    }
+
+    let mut detections = Vec::new();
+    let mut num_boulders = 0;
+    let mut num_fields = 0;
+    for members in fields.iter() {
+        if members.len() >= MIN_BOULDERS_IN_FIELD {
+            // Symbols spread over the field, so that it keeps its extent.
+            let spacing = settings.scaled_length(MIN_FIELD_SYMBOL_SPACING);
+            let mut placed: Vec<Point3D> = Vec::new();
+            for b in members.iter().map(|b| &boulders[*b]) {
+                if !placed.iter().any(|p| p.distance_2d_to(&b.position) < spacing) {
+                    placed.push(b.position);
+                }
+            }
+            detections.push(Detection {
+                terrain: Terrain::Unclassified,
+                triangles: members.iter().map(|b| boulders[*b].triangle).collect(),
+                elevations: Vec::new(),
+                water_level: None,
+                rebuild: None,
+                objects: placed.iter()
+                    .map(|p| ocad::Object::point_object(BOULDER_FIELD, &Sweref { east: p.x, north: p.y }, 0f64))
+                    .collect(),
+            });
+            num_fields = num_fields + 1;
+        } else {
+            for b in members.iter().map(|b| &boulders[*b]) {
+                let symbol = if b.height > LARGE_BOULDER_HEIGHT { LARGE_BOULDER } else { BOULDER };
+                let position = Sweref { east: b.position.x, north: b.position.y };
+                detections.push(Detection {
+                    terrain: Terrain::Unclassified,
+                    triangles: vec![b.triangle],
+                    elevations: Vec::new(),
+                    water_level: None,
+                    rebuild: None,
+                    objects: vec![ocad::Object::point_object(symbol, &position, 0f64)],
+                });
+                num_boulders = num_boulders + 1;
+            }
+        }
+    }
+
+    if verbose {
+        println!("[{}] {} boulders ({} from the ground model) and {} boulder fields created.", &module,
+            num_boulders, from_dtm, num_fields);
+    }
+    detections
+}
@@ -2,65 +2,188 @@
 use super::dtm::{DigitalTerrainModel,Halfedge,TriangleWalk};
 use crate::geometry::Point3D;
 use delaunator::EMPTY;
+use std::collections::HashSet;
 use super::Sweref;
 
 
-pub trait Boundary {
-    fn claim(&mut self, triangle: usize);
-    fn push_halfedge(&mut self, h: Halfedge);
-    fn dtm(&self) -> &DigitalTerrainModel;
-    fn get_halfedges(&self) -> &Vec<Halfedge>;
+// Region growing over the triangulation. A region starts from a seed triangle and takes
+// neighbouring triangles as long as the predicate accepts them. It is grown with an explicit
+// stack, so that large lakes do not overflow the call stack.
 
-    fn should_recurse(&self, halfedge: Halfedge) -> bool;
+#[derive(Clone,Copy,Debug)]
+pub struct RegionLimits {
+    pub min_area: f64,
+    pub max_area: f64,
+    pub max_z_range: f64,
+    pub min_compactness: f64,
+}
 
-    fn add_halfedge(&mut self, halfedge: Halfedge) {
-        assert_ne!(halfedge, EMPTY, "Halfedge is empty!");
-        let opposite = self.dtm().opposite(halfedge);
-        let triangle = opposite / 3;
-        if opposite != EMPTY && self.should_recurse(opposite) {
-            self.claim(triangle);
-            self.add_halfedge(opposite.next());
-            self.add_halfedge(opposite.prev());
-        } else {
-            // Just add the half-edge.
-            self.push_halfedge(halfedge);
+impl RegionLimits {
+    pub fn none() -> RegionLimits {
+        RegionLimits { min_area: 0f64, max_area: f64::INFINITY, max_z_range: f64::INFINITY, min_compactness: 0f64, }
+    }
+
+    // Maximum limits are enforced while growing, minimum limits on the finished region.
+    pub fn accepts(&self, region: &Region) -> bool {
+        region.area >= self.min_area && region.compactness() >= self.min_compactness
+    }
+}
+
+pub struct Region {
+    pub triangles: Vec<usize>,
+    pub area: f64,
+    pub perimeter: f64,
+    pub z_min: f64,
+    pub z_max: f64,
+    // Boundary loops, with the region to the right. Only set once the region is grown.
+    pub loops: Vec<Vec<Halfedge>>,
+    members: HashSet<usize>,
+}
+
+impl Region {
+    fn new() -> Region {
+        Region {
+            triangles: Vec::new(),
+            area: 0f64, perimeter: 0f64,
+            z_min: f64::MAX, z_max: f64::MIN,
+            loops: Vec::new(),
+            members: HashSet::new(),
         }
     }
-    
-    fn grow_from_seed(&mut self, triangle: usize) {
-        self.claim(triangle);
-        self.add_halfedge(triangle*3 + 0);
-        self.add_halfedge(triangle*3 + 1);
-        self.add_halfedge(triangle*3 + 2);
-    }
-
-    fn split_into_outer_edge_and_islands(&self) -> (Vec<Halfedge>, Vec<Vec<Halfedge>>) {
-        let mut remaining = vec![self.get_halfedges().clone()];
-        let mut finished: Vec<Vec<Halfedge>> = Vec::new();
-    
-        while remaining.len() > 0 {
-            let s = split_loop_as_needed(self.dtm(), remaining.pop().unwrap());
-            if let Some(o) = s.1 {
-                if o.len() > 0 { 
-                    remaining.push(o); 
-                } 
-                remaining.push(s.0);
+
+    pub fn contains(&self, triangle: usize) -> bool {
+        self.members.contains(&triangle)
+    }
+
+    pub fn z_range(&self) -> f64 {
+        self.z_max - self.z_min
+    }
+
+    // 1 for a circle, approaching 0 for long and thin regions.
+    pub fn compactness(&self) -> f64 {
+        if self.perimeter > 0f64 { 4f64 * std::f64::consts::PI * self.area / (self.perimeter * self.perimeter) } else { 0f64 }
+    }
+
+    fn claim(&mut self, dtm: &DigitalTerrainModel, triangle: usize) {
+        self.members.insert(triangle);
+        self.triangles.push(triangle);
+        self.area = self.area + dtm.areas[triangle];
+        for h in triangle*3..triangle*3+3 {
+            let length = dtm.length_of_halfedge(h);
+            let opposite = dtm.opposite(h);
+            if opposite != EMPTY && self.contains(opposite / 3) {
+                self.perimeter = self.perimeter - length;
             } else {
-                finished.push(s.0);
+                self.perimeter = self.perimeter + length;
             }
+            let z = dtm.points[dtm.vertices[h]].z;
+            self.z_min = f64::min(self.z_min, z);
+            self.z_max = f64::max(self.z_max, z);
         }
-    
-        // One is clockwise, the rest counter-clockwise.
-        let lake_and_islands: (Vec<Vec<Halfedge>>,Vec<Vec<Halfedge>>) 
-            = finished.into_iter().partition(|a| is_clockwise(self.dtm(), a));
-        assert_eq!(lake_and_islands.0.len(), 1, "Not exactly one clockwise fragment");
-    
-        (lake_and_islands.0.into_iter().next().unwrap(), lake_and_islands.1)
-    }
-    
+    }
+
+    fn would_exceed(&self, dtm: &DigitalTerrainModel, triangle: usize, limits: &RegionLimits) -> bool {
+        let (z_min, z_max) = (triangle*3..triangle*3+3)
+            .map(|h| dtm.points[dtm.vertices[h]].z)
+            .fold((self.z_min, self.z_max), |z, v| (f64::min(z.0, v), f64::max(z.1, v)));
+        self.area + dtm.areas[triangle] > limits.max_area || z_max - z_min > limits.max_z_range
+    }
+
+    fn is_on_boundary(&self, dtm: &DigitalTerrainModel, h: Halfedge) -> bool {
+        let opposite = dtm.opposite(h);
+        opposite == EMPTY || !self.contains(opposite / 3)
+    }
+
+    // Chains the boundary halfedges into loops. The next halfedge starts where the previous
+    // one ends, found by turning around that point through the region.
+    fn trace_loops(&mut self, dtm: &DigitalTerrainModel) {
+        let mut remaining: Vec<Halfedge> = self.triangles.iter()
+            .flat_map(|t| t*3..t*3+3)
+            .filter(|h| self.is_on_boundary(dtm, *h))
+            .collect();
+        remaining.sort();
+        let mut used: HashSet<Halfedge> = HashSet::new();
+        let mut loops = Vec::new();
+        for start in remaining.into_iter() {
+            if used.contains(&start) { continue }
+            let mut boundary_loop = Vec::new();
+            let mut h = start;
+            while used.insert(h) {
+                boundary_loop.push(h);
+                let mut next = h.next();
+                while !self.is_on_boundary(dtm, next) {
+                    next = dtm.opposite(next).next();
+                }
+                h = next;
+            }
+            loops.push(boundary_loop);
+        }
+        self.loops = loops;
+    }
+
+    // The largest clockwise loop, and the rest. Islands are counter-clockwise; a region that
+    // touches itself at a point may also have more than one clockwise loop.
+    pub fn outer_edge_and_islands(&self, dtm: &DigitalTerrainModel) -> (Vec<Halfedge>, Vec<Vec<Halfedge>>) {
+        let outer = self.loops.iter()
+            .enumerate()
+            .filter(|(_, l)| is_clockwise(dtm, l))
+            .fold(None, |best: Option<(usize,f64)>, (i, l)| {
+                let area = f64::abs(signed_area(dtm, l));
+                match best {
+                    Some((_, a)) if a >= area => best,
+                    _ => Some((i, area)),
+                }
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        let islands = self.loops.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != outer)
+            .map(|(_, l)| l.clone())
+            .collect();
+        (self.loops.get(outer).cloned().unwrap_or(Vec::new()), islands)
+    }
 }
 
-fn is_clockwise(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>) -> bool {
+// Grows a region from the seed. The predicate is called with the region so far and a halfedge
+// in the candidate triangle, the other side of which is in the region. Returning true claims
+// the candidate, so the predicate may keep its own state up to date.
+pub fn grow_region<F>(dtm: &DigitalTerrainModel, seed: usize, limits: &RegionLimits, mut accept: F) -> Region
+    where F: FnMut(&Region, Halfedge) -> bool {
+
+    let mut region = Region::new();
+    region.claim(dtm, seed);
+    let mut stack: Vec<Halfedge> = vec![seed*3 + 2, seed*3 + 1, seed*3];
+    while let Some(halfedge) = stack.pop() {
+        let opposite = dtm.opposite(halfedge);
+        if opposite == EMPTY { continue }
+        let triangle = opposite / 3;
+        if region.contains(triangle) || region.would_exceed(dtm, triangle, limits) { continue }
+        if accept(&region, opposite) {
+            region.claim(dtm, triangle);
+            stack.push(opposite.prev());
+            stack.push(opposite.next());
+        }
+    }
+    region.trace_loops(dtm);
+    region
+}
+
+// Connected regions within a set of triangles.
+pub fn regions_within(dtm: &DigitalTerrainModel, triangles: &Vec<usize>) -> Vec<Region> {
+    let members: HashSet<usize> = triangles.iter().cloned().collect();
+    let mut visited: HashSet<usize> = HashSet::new();
+    let mut regions = Vec::new();
+    for seed in triangles.iter() {
+        if visited.contains(seed) { continue }
+        let region = grow_region(dtm, *seed, &RegionLimits::none(), |_, halfedge| members.contains(&(halfedge / 3)));
+        visited.extend(region.triangles.iter().cloned());
+        regions.push(region);
+    }
+    regions
+}
+
+fn signed_area(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>) -> f64 {
     let num_halfedges = halfedges.len();
     let vertices: Vec<&Point3D> = halfedges.iter()
         .cycle()
@@ -71,45 +194,13 @@ fn is_clockwise(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>) -> bool {
     vertices[..]
         .windows(2)
         .map(|p| p[0].x*p[1].y - p[0].y * p[1].x)
-        .sum::<f64>() < 0f64
+        .sum::<f64>() * 0.5
 }
 
-fn split_loop_as_needed(dtm: &DigitalTerrainModel, halfedges: Vec<Halfedge>) -> (Vec<Halfedge>, Option<Vec<Halfedge>>) {
-    let original_length = halfedges.len();
-
-    for (i, h) in halfedges.iter().enumerate() {
-        let opposite = dtm.opposite(*h);
-        if opposite == EMPTY { continue };
-
-        if let Some(steps_from_i_to_opposite) = halfedges.iter()
-            .skip(i)
-            .take(original_length)
-            .position(|m| *m == opposite) {
-            let opposite_located_at = i + steps_from_i_to_opposite;
-            let forward = halfedges.iter().skip(i);
-            let backward = halfedges.iter().take(opposite_located_at+1).rev().map(|h| dtm.opposite(*h));
-            let bridge_length = forward.zip(backward).take_while(|m| *m.0 == m.1).count();
-            // Two different cases to handle:
-            // 1) An appendix: just one or more dangling points, no island on the end.
-            // 2) An actual island.
-            // In the appendix case, bridge_length = opposite + 1 - i, because the iterator "wraps around" at the tip of
-            // the appendix.
-            // In the island case bridge_length*2 + island_length = opposite + 1 - i
-            // NOTE: If i = 0, then we can't be sure that the full length of the bridge has been found. 
-            if bridge_length == steps_from_i_to_opposite + 1 {
-                return (halfedges.into_iter().cycle().skip(opposite_located_at+1).take(original_length - bridge_length).collect(), Some(Vec::new()));
-            } else {
-                let island_length = steps_from_i_to_opposite + 1 - bridge_length*2;
-                let remaining_length = original_length - (steps_from_i_to_opposite + 1);
-                return (halfedges.clone().into_iter().cycle().skip(opposite_located_at+1).take(remaining_length).collect(),
-                    Some(halfedges.into_iter().skip(i + bridge_length).take(island_length).collect()));
-            }
-        }
-    }
-    (halfedges, None)
+fn is_clockwise(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>) -> bool {
+    signed_area(dtm, halfedges) < 0f64
 }
 
-
 pub fn extract_vertices(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>, islands: &Vec<Vec<Halfedge>>) -> Vec<Vec<Sweref>> {
     let mut pts: Vec<Vec<Sweref>> = Vec::new();
     let halfedge_to_sweref = |h: &usize| -> Sweref {
@@ -124,6 +215,13 @@ pub fn extract_vertices(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>, is
 }
 
 pub fn extract_interior_segments(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>) -> Vec<Vec<Sweref>> {
+    extract_segments(dtm, halfedges, |h| !dtm.exterior[h / 3])
+}
+
+// Splits a boundary loop into lines along the runs of halfedges that are kept.
+pub fn extract_segments<F>(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>, keep: F) -> Vec<Vec<Sweref>>
+    where F: Fn(Halfedge) -> bool {
+
     let mut segs: Vec<Vec<Sweref>> = Vec::new();
     let mut cur: Vec<Sweref> = Vec::new();
     let halfedge_to_sweref = |h: &usize| -> Sweref {
@@ -131,12 +229,12 @@ pub fn extract_interior_segments(dtm: &DigitalTerrainModel, halfedges: &Vec<Half
         Sweref { east: p.x, north: p.y, }
     };
     for h in halfedges.iter() {
-        let triangle = h / 3;
-        if dtm.exterior[triangle] && cur.len() > 0 {
+        let kept = keep(*h);
+        if !kept && cur.len() > 0 {
             segs.push(cur);
             cur = Vec::new();
         }
-        if !dtm.exterior[triangle] {
+        if kept {
             cur.push(halfedge_to_sweref(h));
         }
     }
@@ -144,3 +242,59 @@ pub fn extract_interior_segments(dtm: &DigitalTerrainModel, halfedges: &Vec<Half
     segs
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell_triangles(columns: usize, cells: &[(usize,usize)]) -> HashSet<usize> {
+        cells.iter().flat_map(|(i, j)| vec![2*(j*columns + i), 2*(j*columns + i) + 1]).collect()
+    }
+
+    // Grows a region over a 3 x 3 grid without the given cells.
+    fn region_without(dtm: &DigitalTerrainModel, cells: &[(usize,usize)]) -> Region {
+        let excluded = cell_triangles(3, cells);
+        grow_region(dtm, 0, &RegionLimits::none(), |_, halfedge| !excluded.contains(&(halfedge / 3)))
+    }
+
+    fn assert_chained(dtm: &DigitalTerrainModel, boundary_loop: &Vec<Halfedge>) {
+        for (h0, h1) in boundary_loop.iter().zip(boundary_loop.iter().cycle().skip(1)) {
+            assert_eq!(dtm.vertices[h0.next()], dtm.vertices[*h1]);
+        }
+    }
+
+
+    #[test]
+    fn region_with_a_hole() {
+        let dtm = DigitalTerrainModel::grid(3, 3, |_, _| 0f64);
+        let region = region_without(&dtm, &[(1,1)]);
+        assert_eq!(region.triangles.len(), 16);
+        assert_eq!(region.loops.len(), 2);
+        for l in region.loops.iter() { assert_chained(&dtm, l); }
+
+        let (outer, islands) = region.outer_edge_and_islands(&dtm);
+        assert_eq!(outer.len(), 12);
+        assert!(is_clockwise(&dtm, &outer));
+        assert_eq!(islands.len(), 1);
+        assert_eq!(islands[0].len(), 4);
+        assert!(!is_clockwise(&dtm, &islands[0]));
+    }
+
+    #[test]
+    fn region_pinched_at_a_vertex() {
+        // The hole in the middle touches the missing corner cell at the point (2,1), so the
+        // single boundary loop passes that point twice.
+        let dtm = DigitalTerrainModel::grid(3, 3, |_, _| 0f64);
+        let region = region_without(&dtm, &[(1,1), (2,0)]);
+        assert_eq!(region.triangles.len(), 14);
+        assert_eq!(region.loops.len(), 1);
+        assert_chained(&dtm, &region.loops[0]);
+
+        let (outer, islands) = region.outer_edge_and_islands(&dtm);
+        assert_eq!(outer.len(), 16);
+        assert!(islands.is_empty());
+        assert_eq!(signed_area(&dtm, &outer), -7f64);
+        let pinch = 1*4 + 2;
+        assert_eq!(outer.iter().filter(|h| dtm.vertices[**h] == pinch).count(), 2);
+    }
+}
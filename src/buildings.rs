@@ -0,0 +1,227 @@
+use super::las::PointDataRecord;
+use crate::geometry::PointConverter;
+use super::dtm::DigitalTerrainModel;
+use super::feature_index::{FeatureIndex,FeatureKind};
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use super::raster::connected_cells;
+use std::sync::mpsc::Sender;
+use std::collections::{HashMap,HashSet};
+use colored::*;
+
+// Buildings are clusters of class 6 returns. Each cluster is drawn as the smallest rectangle
+// around it, since most buildings are rectangular and the roof outline is ragged. Where a
+// footprint overlaps a building from the map data, only the part outside it is added, so
+// that the two together cover the union.
+
+const BUILDING_CLASS: u8 = 6u8;
+const CELL_SIZE: f64 = 1f64;
+const MIN_POINTS_IN_CELL: usize = 2;
+
+// Sizes at 1:15000. Smaller buildings are dropped, narrow ones widened to the smallest
+// building that can be drawn.
+const MIN_BUILDING_AREA: f64 = 20f64;
+const MIN_BUILDING_SIDE: f64 = 7.5f64;
+
+// Spacing of the samples used to look for buildings from the map data.
+const OVERLAP_SAMPLE_SPACING: f64 = 1f64;
+// Parts outside a building from the map data that are narrower than this at 1:15000 are
+// taken to be misalignment between the map data and the point cloud.
+const MIN_ADDED_SIDE: f64 = 3f64;
+
+const BUILDING: i32 = 521000;
+
+fn cross(o: &Sweref, a: &Sweref, b: &Sweref) -> f64 {
+    (a.east - o.east)*(b.north - o.north) - (a.north - o.north)*(b.east - o.east)
+}
+
+// Monotone chain, counter-clockwise.
+fn convex_hull(mut points: Vec<Sweref>) -> Vec<Sweref> {
+    points.sort_by(|a,b| (a.east, a.north).partial_cmp(&(b.east, b.north)).unwrap_or(std::cmp::Ordering::Equal));
+    let mut hull: Vec<Sweref> = Vec::new();
+    for pass in 0..2 {
+        let start = hull.len();
+        for p in points.iter() {
+            while hull.len() >= start + 2 && cross(&hull[hull.len()-2], &hull[hull.len()-1], p) <= 0f64 {
+                hull.pop();
+            }
+            hull.push(*p);
+        }
+        hull.pop();
+        if pass == 0 { points.reverse(); }
+    }
+    hull
+}
+
+struct Footprint {
+    centre: Sweref,
+    // Unit vector along the first side.
+    direction: (f64,f64),
+    length: f64,
+    width: f64,
+}
+
+impl Footprint {
+    // Rotating calipers: the smallest rectangle has a side along one of the hull edges.
+    fn around(hull: &Vec<Sweref>) -> Option<Footprint> {
+        if hull.len() < 3 { return None }
+        let mut best: Option<(f64,Footprint)> = None;
+        for (a, b) in hull.iter().zip(hull.iter().cycle().skip(1)) {
+            let (dx, dy) = (b.east - a.east, b.north - a.north);
+            let l = f64::sqrt(dx*dx + dy*dy);
+            if l == 0f64 { continue }
+            let (ux, uy) = (dx / l, dy / l);
+            let along: Vec<f64> = hull.iter().map(|p| p.east*ux + p.north*uy).collect();
+            let across: Vec<f64> = hull.iter().map(|p| -p.east*uy + p.north*ux).collect();
+            let (a0, a1) = (along.iter().cloned().fold(f64::MAX, f64::min), along.iter().cloned().fold(f64::MIN, f64::max));
+            let (c0, c1) = (across.iter().cloned().fold(f64::MAX, f64::min), across.iter().cloned().fold(f64::MIN, f64::max));
+            let area = (a1 - a0) * (c1 - c0);
+            if best.as_ref().map(|b| area < b.0).unwrap_or(true) {
+                let (ma, mc) = (0.5f64*(a0 + a1), 0.5f64*(c0 + c1));
+                best = Some((area, Footprint {
+                    centre: Sweref { east: ma*ux - mc*uy, north: ma*uy + mc*ux },
+                    direction: (ux, uy),
+                    length: a1 - a0,
+                    width: c1 - c0,
+                }));
+            }
+        }
+        best.map(|b| b.1)
+    }
+
+    fn area(&self) -> f64 { self.length * self.width }
+
+    fn at(&self, along: f64, across: f64) -> Sweref {
+        let (ux, uy) = self.direction;
+        Sweref { east: self.centre.east + along*ux - across*uy, north: self.centre.north + along*uy + across*ux }
+    }
+
+    fn corners(&self) -> Vec<Sweref> {
+        let (l, w) = (0.5f64*self.length, 0.5f64*self.width);
+        vec![self.at(-l, -w), self.at(l, -w), self.at(l, w), self.at(-l, w)]
+    }
+
+    // Whether the samples across the footprint are outside the buildings from the map data.
+    // Rows run along the footprint, one per sample across it.
+    fn samples_outside(&self, features: &FeatureIndex) -> Vec<Vec<bool>> {
+        let nl = usize::max(1, (self.length / OVERLAP_SAMPLE_SPACING).ceil() as usize);
+        let nw = usize::max(1, (self.width / OVERLAP_SAMPLE_SPACING).ceil() as usize);
+        (0..nw).map(|j| (0..nl).map(|i| {
+            let p = self.at(self.length * ((i as f64 + 0.5f64) / (nl as f64) - 0.5f64), self.width * ((j as f64 + 0.5f64) / (nw as f64) - 0.5f64));
+            !features.is_inside(FeatureKind::Building, &p)
+        }).collect()).collect()
+    }
+
+    // The samples outside as rectangles. Consecutive rows with the same run of samples
+    // outside are joined into one rectangle.
+    fn parts_outside(&self, outside: &Vec<Vec<bool>>) -> Vec<Footprint> {
+        let (nw, nl) = (outside.len(), outside[0].len());
+        let (dl, dw) = (self.length / (nl as f64), self.width / (nw as f64));
+        let part = |i0: usize, i1: usize, j0: usize, j1: usize| Footprint {
+            centre: self.at(dl * 0.5f64 * ((i0 + i1 + 1) as f64) - 0.5f64*self.length, dw * 0.5f64 * ((j0 + j1 + 1) as f64) - 0.5f64*self.width),
+            direction: self.direction,
+            length: dl * ((i1 - i0 + 1) as f64),
+            width: dw * ((j1 - j0 + 1) as f64),
+        };
+
+        let mut parts = Vec::new();
+        // Runs (first, last) in the previous rows, and the row they started in.
+        let mut open: Vec<(usize,usize,usize)> = Vec::new();
+        for j in 0..=nw {
+            let mut runs: Vec<(usize,usize)> = Vec::new();
+            if j < nw {
+                let mut i = 0;
+                while i < nl {
+                    if !outside[j][i] { i = i + 1; continue }
+                    let first = i;
+                    while i < nl && outside[j][i] { i = i + 1; }
+                    runs.push((first, i - 1));
+                }
+            }
+            let mut still_open = Vec::new();
+            for (i0, i1, j0) in open.into_iter() {
+                if runs.contains(&(i0, i1)) {
+                    still_open.push((i0, i1, j0));
+                } else {
+                    parts.push(part(i0, i1, j0, j - 1));
+                }
+            }
+            for (i0, i1) in runs.into_iter() {
+                if !still_open.iter().any(|r| (r.0, r.1) == (i0, i1)) {
+                    still_open.push((i0, i1, j));
+                }
+            }
+            open = still_open;
+        }
+        parts
+    }
+}
+
+pub fn extract_buildings(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, features: &FeatureIndex, settings: &MapSettings,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "BUILDING".red();
+    let mut cells: HashMap<(i64,i64),Vec<Sweref>> = HashMap::new();
+    for record in records.iter().filter(|r| r.classification() == BUILDING_CLASS) {
+        let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+        if !dtm.bounds.contains_2d(&p) { continue }
+        let cell = ((p.x / CELL_SIZE).floor() as i64, (p.y / CELL_SIZE).floor() as i64);
+        cells.entry(cell).or_insert(Vec::new()).push(Sweref { east: p.x, north: p.y });
+    }
+
+    // Connected groups of cells with roof returns.
+    let roof: HashSet<(i64,i64)> = cells.iter()
+        .filter(|(_, points)| points.len() >= MIN_POINTS_IN_CELL)
+        .map(|(c, _)| *c)
+        .collect();
+    let mut num_clusters = 0;
+    let mut num_merged = 0;
+    let mut num_in_map_data = 0;
+    let mut num_buildings = 0;
+    for group in connected_cells(&roof).iter() {
+        num_clusters = num_clusters + 1;
+
+        let points: Vec<Sweref> = group.iter().flat_map(|c| cells[c].iter().cloned()).collect();
+        let mut footprint = match Footprint::around(&convex_hull(points)) {
+            Some(f) => f,
+            None => continue,
+        };
+        if footprint.area() < settings.scaled_area(MIN_BUILDING_AREA) { continue }
+
+        let outside = footprint.samples_outside(features);
+        if outside.iter().any(|row| row.iter().any(|o| !o)) {
+            // Only what the building from the map data does not cover is added to it.
+            let parts: Vec<Footprint> = footprint.parts_outside(&outside).into_iter()
+                .filter(|p| p.length >= settings.scaled_length(MIN_ADDED_SIDE) && p.width >= settings.scaled_length(MIN_ADDED_SIDE))
+                .filter(|p| p.area() >= settings.scaled_area(MIN_BUILDING_AREA))
+                .collect();
+            if parts.len() == 0 {
+                num_in_map_data = num_in_map_data + 1;
+                continue;
+            }
+            for part in parts.iter() {
+                ocad::post_objects_without_clipping(
+                    vec![part.corners()],
+                    &vec![ocad::GraphSymbol::Fill(BUILDING)],
+                    post_box);
+            }
+            num_merged = num_merged + 1;
+            continue;
+        }
+        footprint.length = f64::max(footprint.length, settings.scaled_length(MIN_BUILDING_SIDE));
+        footprint.width = f64::max(footprint.width, settings.scaled_length(MIN_BUILDING_SIDE));
+
+        ocad::post_objects_without_clipping(
+            vec![footprint.corners()],
+            &vec![ocad::GraphSymbol::Fill(BUILDING)],
+            post_box);
+        num_buildings = num_buildings + 1;
+    }
+
+    if verbose {
+        println!("[{}] {} clusters of building points, {} new buildings, {} buildings from the map data extended and {} already in it.", &module,
+            num_clusters, num_buildings, num_merged, num_in_map_data);
+    }
+}
@@ -1,15 +1,18 @@
-use super::dtm::{DigitalTerrainModel,Z_NORMAL,Halfedge,Terrain};
+use super::dtm::{DigitalTerrainModel,Z_NORMAL,Halfedge,Terrain,TriangleWalk};
 use super::ocad;
-use std::sync::mpsc::Sender;
-use super::boundary::Boundary;
+use super::detection::Detection;
+use super::boundary::{grow_region,RegionLimits};
 use super::geometry::{Plane,Point3D};
-use std::cmp::Ordering;
 use ::geo::{Coordinate,LineString};
 use ::geo::algorithm::simplifyvw::SimplifyVW;
 use ::geo::algorithm::euclidean_length::EuclideanLength;
 use super::Sweref;
 use colored::*;
 use std::f64;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use delaunator::EMPTY;
+use super::map_settings::MapSettings;
 
 const MAX_ALLOWED_EDGE: f64 = 10.0;
 const MAX_ZNORMAL_FOR_SEED: f64 = 0.5f64;
@@ -17,38 +20,194 @@ const MAX_ZNORMAL_FOR_GROW: f64 = 0.8f64;
 const MIN_ANGLE_TO_VERTICAL: f64 = 70f64;
 const MIN_REQUIRED_HEIGHT: f64 = 1.2f64;
 const MIN_REQUIRED_Z_DIFF: f64 = 0.45f64;
-const UNPASSABLE_CLIFF: f64 = 1.5f64; // Height is overestimated
+const UNPASSABLE_CLIFF: f64 = 1.5f64;
+
+// Heights are measured on profiles across the cliff line, from the top of the steep part to
+// its foot. Profiles start a bit behind the top edge, since the line is simplified.
+const PROFILE_SPACING: f64 = 2f64;
+const PROFILE_BEHIND: f64 = 2f64;
+const PROFILE_AHEAD: f64 = 8f64;
+const PROFILE_STEP: f64 = 0.25f64;
+const MIN_SLOPE_IN_CLIFF: f64 = 1f64;
+
+// Cliffs are often broken by triangles that are too flat. Flatter triangles are accepted
+// if they continue the cliff along its strike and lie close to the plane of the cliff face.
+const MAX_ZNORMAL_FOR_BRIDGE: f64 = 0.95f64;
+const MAX_DISTANCE_TO_PLANE: f64 = 0.5f64;
+const MIN_COSINE_TO_STRIKE: f64 = 0.7f64;
+const MAX_BRIDGING_TRIANGLES: usize = 3;
+const MAX_GAP_IN_TOP_EDGE: usize = 2;
+const MIN_TRIANGLES_FOR_PLANE: usize = 4;
+const TRIANGLES_BETWEEN_PLANE_FITS: usize = 4;
 
 struct Cliff<'a> {
-    halfedges: Vec<Halfedge>,
-    index: usize,
     dtm: &'a DigitalTerrainModel,
-    indices_for_each_triangle: &'a mut Vec<usize>,
-
     normals: &'a Vec<[f64;3]>,
     z_limits: &'a Vec<(f64,f64)>,
+
+    // Running fit of the cliff face, and the number of flat triangles between each claimed
+    // triangle and the steep part of the cliff.
+    incenters: Vec<Point3D>,
+    plane: Option<Plane>,
+    bridging: HashMap<usize,usize>,
 }
 
-impl<'a> Boundary for Cliff<'a> {
-    fn claim(&mut self, triangle: usize) { self.indices_for_each_triangle[triangle] = self.index; }
-    fn push_halfedge(&mut self, h: Halfedge) { self.halfedges.push(h); }
-    fn dtm(&self) -> &DigitalTerrainModel { self.dtm }
-    fn get_halfedges(&self) -> &Vec<Halfedge> { &self.halfedges }
+impl<'a> Cliff<'a> {
+    fn is_steep(&self, t: usize) -> bool {
+        self.normals[t][Z_NORMAL] < MAX_ZNORMAL_FOR_GROW &&
+        self.z_limits[t].1 - self.z_limits[t].0 > MIN_REQUIRED_Z_DIFF
+    }
 
-    fn should_recurse(&self, halfedge: Halfedge) -> bool {
+    fn bridges_gap(&self, halfedge: Halfedge) -> bool {
+        let t = halfedge / 3;
+        let from = self.dtm.opposite(halfedge) / 3;
+        let plane = match &self.plane {
+            Some(p) if p.angle_to_vertical() > MIN_ANGLE_TO_VERTICAL => p,
+            _ => return false,
+        };
+        let (sx, sy) = match plane.strike() { Some(s) => s, None => return false };
+        if self.normals[t][Z_NORMAL] >= MAX_ZNORMAL_FOR_BRIDGE ||
+            self.bridging.get(&from).map(|n| n + 1 > MAX_BRIDGING_TRIANGLES).unwrap_or(true) {
+            return false;
+        }
+
+        let p = self.dtm.triangle_incenter(t);
+        let q = self.dtm.triangle_incenter(from);
+        let (dx, dy) = (p.x - q.x, p.y - q.y);
+        let d = f64::sqrt(dx*dx + dy*dy);
+        d > 0f64 &&
+        f64::abs(dx*sx + dy*sy) / d > MIN_COSINE_TO_STRIKE &&
+        f64::abs(plane.distance_to(&p)) < MAX_DISTANCE_TO_PLANE
+    }
+
+    fn claim(&mut self, triangle: usize) {
+        let bridging = if self.is_steep(triangle) { 0 } else {
+            (0..3).filter_map(|i| {
+                let o = self.dtm.opposite(triangle*3 + i);
+                if o == EMPTY { None } else { self.bridging.get(&(o/3)).map(|n| n + 1) }
+            }).min().unwrap_or(0)
+        };
+        self.bridging.insert(triangle, bridging);
+
+        self.incenters.push(self.dtm.triangle_incenter(triangle));
+        let n = self.incenters.len();
+        if n >= MIN_TRIANGLES_FOR_PLANE && (n - MIN_TRIANGLES_FOR_PLANE) % TRIANGLES_BETWEEN_PLANE_FITS == 0 {
+            self.plane = Plane::from_points(&self.incenters);
+        }
+    }
+
+    fn should_grow(&self, halfedge: Halfedge) -> bool {
         let t = halfedge / 3;
-        self.indices_for_each_triangle[t] == 0 &&
-        self.normals[t][Z_NORMAL] < MAX_ZNORMAL_FOR_GROW && 
         !self.dtm.exterior[t] &&
-        self.z_limits[t].1 - self.z_limits[t].0 > MIN_REQUIRED_Z_DIFF &&
         self.dtm.terrain[t] == Terrain::Unclassified &&
+        (self.is_steep(t) || self.bridges_gap(halfedge)) &&
         self.dtm.length_of_halfedge(halfedge) < MAX_ALLOWED_EDGE
     }
 }
 
-pub fn detect_cliffs(dtm: &mut DigitalTerrainModel, 
-            post_box: &Sender<ocad::Object>,
-            verbose: bool) {
+// Boundary halfedges along the top of the cliff: above the middle of the face, and with the
+// cliff falling away from them. Short interruptions are bridged, and the longest run is used.
+fn top_edge_of_cliff(dtm: &DigitalTerrainModel, halfedges: &Vec<Halfedge>, average_z: f64) -> Vec<Coordinate<f64>> {
+    let is_top: Vec<bool> = halfedges.iter()
+        .map(|h| {
+            let a = dtm.points[dtm.vertices[*h]];
+            let b = dtm.points[dtm.vertices[h.next()]];
+            let c = dtm.points[dtm.vertices[h.prev()]];
+            let z = (a.z + b.z) * 0.5;
+            z > average_z && z > c.z
+        })
+        .collect();
+    let n = halfedges.len();
+
+    // Start the search after an edge that is not on top, so that runs do not wrap around.
+    let (start, best) = match is_top.iter().position(|t| !t) {
+        None => (0, (0, n)),
+        Some(start) => {
+            let mut best = (0, 0);
+            let mut run: Option<(usize,usize)> = None;
+            let mut gap = 0;
+            for i in 0..n {
+                if is_top[(start + i) % n] {
+                    run = match run {
+                        Some((first, _)) => Some((first, i + 1)),
+                        None => Some((i, i + 1)),
+                    };
+                    gap = 0;
+                } else {
+                    gap = gap + 1;
+                    if gap > MAX_GAP_IN_TOP_EDGE { run = None; }
+                }
+                if let Some((first, end)) = run {
+                    if end - first > best.1 - best.0 { best = (first, end); }
+                }
+            }
+            (start, best)
+        }
+    };
+
+    let run: Vec<Halfedge> = (best.0..best.1).map(|i| halfedges[(start + i) % n]).collect();
+    let to_coordinate = |h: &Halfedge| {
+        let p = dtm.points[dtm.vertices[*h]];
+        Coordinate { x: p.x, y: p.y, }
+    };
+    let mut points: Vec<Coordinate<f64>> = run.iter().map(to_coordinate).collect();
+    if let Some(last) = run.last() {
+        points.push(to_coordinate(&last.next()));
+    }
+    points
+}
+
+// Height of the steepest drop on each profile to the right of the line, i.e. downhill.
+fn profile_heights(dtm: &DigitalTerrainModel, linestring: &LineString<f64>) -> Vec<f64> {
+    let mut heights = Vec::new();
+    let mut hint = 0usize;
+    let mut to_next_profile = PROFILE_SPACING * 0.5;
+    for s in linestring.0.windows(2) {
+        let dx = s[1].x - s[0].x;
+        let dy = s[1].y - s[0].y;
+        let length = f64::sqrt(dx*dx + dy*dy);
+        if length == 0f64 { continue }
+        let (rx, ry) = (dy / length, -dx / length);
+
+        let mut along = to_next_profile;
+        while along < length {
+            let x0 = s[0].x + dx * along / length;
+            let y0 = s[0].y + dy * along / length;
+
+            let mut zs: Vec<f64> = Vec::new();
+            let mut offset = -PROFILE_BEHIND;
+            while offset <= PROFILE_AHEAD {
+                let p = Point3D { x: x0 + rx*offset, y: y0 + ry*offset, z: 0f64 };
+                match dtm.triangle_containing_point(&p, hint) {
+                    Some(t) => { hint = t; zs.push(dtm.z_coordinate_in_triangle(&p, t)); },
+                    None => { zs.clear(); break; },
+                }
+                offset = offset + PROFILE_STEP;
+            }
+
+            // The steep run with the largest drop.
+            let mut best = 0f64;
+            let mut top: Option<f64> = None;
+            for z in zs.windows(2) {
+                if (z[0] - z[1]) / PROFILE_STEP > MIN_SLOPE_IN_CLIFF {
+                    let t = *top.get_or_insert(z[0]);
+                    best = f64::max(best, t - z[1]);
+                } else {
+                    top = None;
+                }
+            }
+            if best > 0f64 { heights.push(best); }
+
+            along = along + PROFILE_SPACING;
+        }
+        to_next_profile = along - length;
+    }
+    heights
+}
+
+pub fn detect_cliffs(dtm: &DigitalTerrainModel, 
+            settings: &MapSettings,
+            verbose: bool) -> Vec<Detection> {
 
     // Identify seed triangles: edges < 5 m, z-normal < 0.3.
     let normals = dtm.normals();
@@ -73,7 +232,7 @@ pub fn detect_cliffs(dtm: &mut DigitalTerrainModel,
     let mut cliff_index_per_triangle = vec![0 as usize; dtm.num_triangles];
     
     let mut cliff_index: usize = 1;
-    let mut num_cliffs_output = 0;
+    let mut detections = Vec::new();
 
     for seed_triangle in seed_triangles.into_iter() {
         // Take a seed triangle.
@@ -81,56 +240,38 @@ pub fn detect_cliffs(dtm: &mut DigitalTerrainModel,
         if cliff_index_per_triangle[seed_triangle] != 0 { continue };
 
         let mut cliff = Cliff {
-            halfedges: Vec::new(),
-            index: cliff_index,
             dtm: dtm,
-            indices_for_each_triangle: &mut cliff_index_per_triangle,
             normals: &normals, z_limits: &z_limits,
+            incenters: Vec::new(), plane: None, bridging: HashMap::new(),
         };
 
-        cliff.grow_from_seed(seed_triangle);
-        let (halfedges, islands) = cliff.split_into_outer_edge_and_islands();
-
-        let height = {
-            let (z_min, z_max) = halfedges.iter()
-                .fold((f64::MAX,f64::MIN), |z, h| {
-                    let p = dtm.points[dtm.vertices[*h]];
-                    (   if p.z < z.0 { p.z } else { z.0 },
-                        if p.z > z.1 { p.z } else { z.1 })
-                });
-            z_max - z_min
-        };
-
-        if height > MIN_REQUIRED_HEIGHT && halfedges.len() > 3 {
+        cliff.claim(seed_triangle);
+        let region = grow_region(dtm, seed_triangle, &RegionLimits::none(), |_, halfedge| {
+            let t = halfedge / 3;
+            let accepted = cliff_index_per_triangle[t] == 0 && cliff.should_grow(halfedge);
+            if accepted { cliff.claim(t); }
+            accepted
+        });
+        for t in region.triangles.iter() {
+            cliff_index_per_triangle[*t] = cliff_index;
+        }
+        let (halfedges, _) = region.outer_edge_and_islands(dtm);
 
-            let incenters: Vec<Point3D> = cliff.indices_for_each_triangle.iter()
-            .enumerate()
-            .filter_map(|(i,c)| if *c == cliff_index { 
-                Some(dtm.triangle_incenter(i)) 
-            } else { 
-                None 
-            })
-            .collect();
+        // Only a coarse filter. The region includes slope above and below the cliff.
+        if region.z_range() > MIN_REQUIRED_HEIGHT && halfedges.len() > 3 {
 
             // Create plane from incenters and verify that angle to vertical is low enough.
-            match Plane::from_points(&incenters) {
+            match Plane::from_points(&cliff.incenters) {
                 Some(plane) if plane.angle_to_vertical() > MIN_ANGLE_TO_VERTICAL => {
 
-                // Sort points along projection onto intersection with average z.
-                let (a,b) = plane.intersection_with_z(plane.average_z);
-                let ab = b - a;
-                let mut projections: Vec<(f64, Coordinate<f64>)> = incenters.iter()
-                    .map(|p| {
-                        let ap = *p - a;
-                        (ap.dot(&ab) / ab.dot(&ab), Coordinate { x: p.x, y: p.y, })
-                    }).collect();
-
-                projections.sort_by(|a,b| if a.0 < b.0 { Ordering::Less } else { Ordering::Greater });
-                let ordered_points: Vec<Coordinate<f64>> = projections.into_iter().map(|(_,p)| p).collect();
-                let linestring = LineString::from(ordered_points).simplifyvw(&10.0);
-                if linestring.euclidean_length() > 4.0 { 
-                    // curve reconstruction from unorganized points is non-trivial.
-                    // I've experimented a bit with it but without success.
+                // The line follows the top edge, in boundary order. The cliff is to the right of
+                // the outer boundary, so OCAD draws the tags downhill.
+                let top_edge = top_edge_of_cliff(dtm, &halfedges, plane.average_z);
+                let linestring = LineString::from(top_edge).simplifyvw(&settings.scaled_area(10.0));
+                let mut heights = profile_heights(dtm, &linestring);
+                if linestring.euclidean_length() > settings.scaled_length(4.0) && heights.len() > 0 { 
+                    heights.sort_by(|a,b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                    let height = heights[heights.len() / 2];
 
                     let segments = linestring
                         .points_iter()
@@ -140,19 +281,19 @@ pub fn detect_cliffs(dtm: &mut DigitalTerrainModel,
                             if x.0 == 0 { ocad::Segment::Move(s) } else { ocad::Segment::Line(s) }
                         }).collect();
 
-                    post_box.send(ocad::Object {
-                        object_type: ocad::ObjectType::Line(false),
-                        symbol_number: if height > UNPASSABLE_CLIFF { 201000 } else { 202000 },
-                        segments,
-                    }).expect("Unable to send cliff!");
-
-                    num_cliffs_output = num_cliffs_output + 1;
-
-                    for (i,c) in cliff_index_per_triangle.iter().enumerate() {
-                        if *c == cliff_index {
-                            dtm.terrain[i] = Terrain::Cliff;
-                        }
-                    }
+                    detections.push(Detection {
+                        terrain: Terrain::Cliff,
+                        triangles: region.triangles.clone(),
+                        elevations: Vec::new(),
+                        water_level: None,
+                        objects: vec![ocad::Object {
+                            object_type: ocad::ObjectType::Line(false),
+                            symbol_number: if height > UNPASSABLE_CLIFF { 201000 } else { 202000 },
+                            segments,
+                            height: Some(height),
+                        }],
+                        rebuild: None,
+                    });
                 }},
                 _ => {},            
             };
@@ -162,6 +303,7 @@ pub fn detect_cliffs(dtm: &mut DigitalTerrainModel,
     }
     if verbose {
         let module = "CLIFF".black();
-        println!("[{}] {} cliffs created.", &module, num_cliffs_output);
+        println!("[{}] {} cliffs created.", &module, detections.len());
     }
+    detections
 }
\ No newline at end of file
@@ -0,0 +1,238 @@
+use crate::contours::Contour;
+use crate::geometry::Bounds;
+
+// Nesting of contours over the whole map. Each contour becomes a polygon that encloses the
+// higher or lower ground next to it. Closed contours are used as they are. Open contours
+// are closed by following the map edge around the uphill side, so that they can act
+// as super-contours for the closed contours on that side.
+
+// How far inside its polygon a contour is probed, in m.
+const PROBE_OFFSET: f64 = 0.01f64;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Landform {
+    Knoll,
+    Depression,
+    Slope,
+}
+
+#[derive(Debug)]
+pub struct ContourNode {
+    pub contour: usize,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub elevation: f64,
+    pub closed: bool,
+    pub encloses_higher_ground: bool,
+    pub area: f64,
+    polygon: Vec<(f64,f64)>,
+    // A point strictly inside the polygon, away from the map edge.
+    probe: (f64,f64),
+    lower_left: (f64,f64),
+    upper_right: (f64,f64),
+}
+
+pub struct ContourTree {
+    pub nodes: Vec<ContourNode>,
+    pub roots: Vec<usize>,
+}
+
+fn signed_area(polygon: &Vec<(f64,f64)>) -> f64 {
+    polygon.iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(p0, p1)| p0.0*p1.1 - p1.0*p0.1)
+        .sum::<f64>() * 0.5
+}
+
+fn polygon_contains(polygon: &Vec<(f64,f64)>, p: &(f64,f64)) -> bool {
+    let mut inside = false;
+    for (a, b) in polygon.iter().zip(polygon.iter().cycle().skip(1)) {
+        if (a.1 > p.1) != (b.1 > p.1) &&
+            p.0 < a.0 + (p.1 - a.1) * (b.0 - a.0) / (b.1 - a.1) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// A point just inside the polygon, beside the middle of the contour. Points on the map edge
+// lie on the boundary of every polygon that was closed along it, and are no good for ray
+// casting. The first num_contour_points of the polygon are the contour.
+fn interior_point(polygon: &Vec<(f64,f64)>, num_contour_points: usize) -> (f64,f64) {
+    let side = if signed_area(polygon) > 0f64 { 1f64 } else { -1f64 };
+    let middle = num_contour_points / 2;
+    (0..num_contour_points-1)
+        .map(|k| (middle + k) % (num_contour_points - 1))
+        .filter_map(|k| {
+            let (a, b) = (polygon[k], polygon[k+1]);
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let length = f64::sqrt(dx*dx + dy*dy);
+            if length == 0f64 { return None }
+            let offset = side * f64::min(PROBE_OFFSET, 0.1f64 * length) / length;
+            let p = (0.5f64*(a.0 + b.0) - dy*offset, 0.5f64*(a.1 + b.1) + dx*offset);
+            if polygon_contains(polygon, &p) { Some(p) } else { None }
+        })
+        .next()
+        .unwrap_or(polygon[0])
+}
+
+// Position along the map edge, counter-clockwise from the south-west corner.
+fn position_along_edge(bounds: &Bounds, p: &(f64,f64)) -> f64 {
+    let w = bounds.upper.x - bounds.lower.x;
+    let h = bounds.upper.y - bounds.lower.y;
+    let distances = [
+        p.1 - bounds.lower.y,
+        bounds.upper.x - p.0,
+        bounds.upper.y - p.1,
+        p.0 - bounds.lower.x,
+    ];
+    let edge = (0..4).fold(0, |best, i| if f64::abs(distances[i]) < f64::abs(distances[best]) { i } else { best });
+    let x = f64::max(bounds.lower.x, f64::min(bounds.upper.x, p.0));
+    let y = f64::max(bounds.lower.y, f64::min(bounds.upper.y, p.1));
+    match edge {
+        0 => x - bounds.lower.x,
+        1 => w + y - bounds.lower.y,
+        2 => w + h + bounds.upper.x - x,
+        _ => w + h + w + bounds.upper.y - y,
+    }
+}
+
+// Closes an open contour by walking along the map edge from its end back to its start.
+// Walking counter-clockwise puts the left side of the contour inside the polygon.
+fn close_along_edge(bounds: &Bounds, points: &mut Vec<(f64,f64)>, counter_clockwise: bool) {
+    let w = bounds.upper.x - bounds.lower.x;
+    let h = bounds.upper.y - bounds.lower.y;
+    let perimeter = 2f64*(w + h);
+    let corners = [
+        (w, (bounds.upper.x, bounds.lower.y)),
+        (w + h, (bounds.upper.x, bounds.upper.y)),
+        (w + h + w, (bounds.lower.x, bounds.upper.y)),
+        (perimeter, (bounds.lower.x, bounds.lower.y)),
+    ];
+
+    let start = position_along_edge(bounds, &points[0]);
+    let end = position_along_edge(bounds, &points[points.len()-1]);
+
+    // Distance travelled from the end point to each corner, in the direction of travel.
+    let mut passed: Vec<(f64,(f64,f64))> = corners.iter()
+        .map(|(s, corner)| {
+            let d = if counter_clockwise { s - end } else { end - s };
+            ((d + perimeter) % perimeter, *corner)
+        })
+        .collect();
+    let total = if counter_clockwise { start - end } else { end - start };
+    let total = (total + perimeter) % perimeter;
+    passed.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    for (d, corner) in passed.into_iter() {
+        if d < total { points.push(corner); }
+    }
+}
+
+impl ContourTree {
+    pub fn build(contours: &Vec<Contour>, bounds: &Bounds) -> ContourTree {
+        let mut nodes: Vec<ContourNode> = contours.iter()
+            .enumerate()
+            .filter(|(_, c)| c.linestring.0.len() >= 3)
+            .map(|(i, c)| {
+                let mut polygon: Vec<(f64,f64)> = c.linestring.0.iter().map(|p| (p.x, p.y)).collect();
+                let num_contour_points = polygon.len();
+                let encloses_higher_ground = if c.closed {
+                    // A counter-clockwise ring has its inside to the left.
+                    (signed_area(&polygon) > 0f64) == c.uphill_on_left
+                } else {
+                    close_along_edge(bounds, &mut polygon, c.uphill_on_left);
+                    true
+                };
+                let lower_left = polygon.iter().fold((f64::MAX, f64::MAX), |m, p| (f64::min(m.0, p.0), f64::min(m.1, p.1)));
+                let upper_right = polygon.iter().fold((f64::MIN, f64::MIN), |m, p| (f64::max(m.0, p.0), f64::max(m.1, p.1)));
+                let probe = interior_point(&polygon, num_contour_points);
+                ContourNode {
+                    contour: i,
+                    parent: None,
+                    children: Vec::new(),
+                    elevation: c.base_elevation,
+                    closed: c.closed,
+                    encloses_higher_ground,
+                    area: f64::abs(signed_area(&polygon)),
+                    polygon, probe, lower_left, upper_right,
+                }
+            })
+            .collect();
+
+        // The parent is the smallest polygon that contains the node.
+        for i in 0..nodes.len() {
+            let probe = nodes[i].probe;
+            let parent = nodes.iter()
+                .enumerate()
+                .filter(|(j, n)| *j != i &&
+                    n.area > nodes[i].area &&
+                    n.lower_left.0 <= nodes[i].lower_left.0 && n.lower_left.1 <= nodes[i].lower_left.1 &&
+                    n.upper_right.0 >= nodes[i].upper_right.0 && n.upper_right.1 >= nodes[i].upper_right.1 &&
+                    polygon_contains(&n.polygon, &probe))
+                .fold(None, |best: Option<(usize,f64)>, (j, n)| match best {
+                    Some((_, area)) if area <= n.area => best,
+                    _ => Some((j, n.area)),
+                })
+                .map(|(j, _)| j);
+            nodes[i].parent = parent;
+        }
+
+        let mut roots = Vec::new();
+        for i in 0..nodes.len() {
+            match nodes[i].parent {
+                Some(p) => nodes[p].children.push(i),
+                None => roots.push(i),
+            }
+        }
+
+        ContourTree { nodes, roots }
+    }
+
+    // A point inside the node's polygon, next to its contour.
+    pub fn probe(&self, node: usize) -> (f64,f64) {
+        self.nodes[node].probe
+    }
+
+    pub fn depth(&self, node: usize) -> usize {
+        let mut depth = 0;
+        let mut n = node;
+        while let Some(p) = self.nodes[n].parent {
+            depth = depth + 1;
+            n = p;
+        }
+        depth
+    }
+
+    pub fn landform(&self, node: usize) -> Landform {
+        let n = &self.nodes[node];
+        if !n.closed || n.children.len() > 0 {
+            Landform::Slope
+        } else if n.encloses_higher_ground {
+            Landform::Knoll
+        } else {
+            Landform::Depression
+        }
+    }
+
+    pub fn nodes_with_landform(&self, landform: Landform) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|n| self.landform(*n) == landform).collect()
+    }
+
+    // Nodes whose nesting contradicts their elevation. Inside higher ground, no contour may
+    // be lower than its parent, and vice versa. Children are also at most one step away.
+    pub fn inconsistencies(&self, max_step: f64) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|i| {
+            let n = &self.nodes[*i];
+            match n.parent {
+                Some(p) => {
+                    let parent = &self.nodes[p];
+                    let dz = n.elevation - parent.elevation;
+                    f64::abs(dz) > max_step ||
+                    (parent.encloses_higher_ground && dz < 0f64 && n.encloses_higher_ground) ||
+                    (!parent.encloses_higher_ground && dz > 0f64 && !n.encloses_higher_ground)
+                },
+                None => false,
+            }
+        }).collect()
+    }
+}
@@ -0,0 +1,188 @@
+use crate::contours::Contour;
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use std::collections::{HashMap,HashSet};
+use ::geo::Coordinate;
+use colored::*;
+use super::map_settings::MapSettings;
+
+// Simplification can make neighbouring contours cross or touch, mostly on steep slopes.
+// The segments involved in an intersection are simplified again from the traced points with
+// decreasing tolerances, the last one restoring them. The rest of each contour is left alone. Tolerances are areas at 1:15000.
+const REPAIR_TOLERANCES: [f64;3] = [2.0f64, 0.5f64, 0f64];
+const CELL_SIZE: f64 = 20f64;
+
+
+pub struct Intersection {
+    pub contours: (usize,usize),
+    // Index of the first point of the crossing segment on each contour.
+    pub segments: (usize,usize),
+    pub location: Coordinate<f64>,
+}
+
+fn cross(a: &Coordinate<f64>, b: &Coordinate<f64>) -> f64 {
+    a.x*b.y - a.y*b.x
+}
+
+fn segment_intersection(a0: &Coordinate<f64>, a1: &Coordinate<f64>, b0: &Coordinate<f64>, b1: &Coordinate<f64>) -> Option<Coordinate<f64>> {
+    let r = Coordinate { x: a1.x - a0.x, y: a1.y - a0.y };
+    let s = Coordinate { x: b1.x - b0.x, y: b1.y - b0.y };
+    let q = Coordinate { x: b0.x - a0.x, y: b0.y - a0.y };
+    let denominator = cross(&r, &s);
+
+    if denominator == 0f64 {
+        // Parallel. Only collinear, overlapping segments touch.
+        let rr = r.x*r.x + r.y*r.y;
+        if cross(&q, &r) != 0f64 || rr == 0f64 { return None }
+        let t0 = (q.x*r.x + q.y*r.y) / rr;
+        let t1 = t0 + (s.x*r.x + s.y*r.y) / rr;
+        let (lo, hi) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+        if hi < 0f64 || lo > 1f64 { return None }
+        let t = f64::max(lo, 0f64);
+        return Some(Coordinate { x: a0.x + t*r.x, y: a0.y + t*r.y });
+    }
+
+    let t = cross(&q, &s) / denominator;
+    let u = cross(&q, &r) / denominator;
+    if t >= 0f64 && t <= 1f64 && u >= 0f64 && u <= 1f64 {
+        Some(Coordinate { x: a0.x + t*r.x, y: a0.y + t*r.y })
+    } else {
+        None
+    }
+}
+
+fn cell(x: f64, y: f64) -> (i64,i64) {
+    ((x / CELL_SIZE).floor() as i64, (y / CELL_SIZE).floor() as i64)
+}
+
+// Number of segments, including the one from the last point back to the first on closed
+// contours.
+fn num_segments(contour: &Contour) -> usize {
+    let n = contour.linestring.0.len();
+    if n < 2 { 0 } else if contour.closed && n > 2 { n } else { n - 1 }
+}
+
+fn segment(contour: &Contour, i: usize) -> (&Coordinate<f64>, &Coordinate<f64>) {
+    let points = &contour.linestring.0;
+    (&points[i], &points[(i+1) % points.len()])
+}
+
+pub fn find_intersections(contours: &Vec<Contour>) -> Vec<Intersection> {
+    // Segments are indexed by (contour, index of first point).
+    let mut grid: HashMap<(i64,i64),Vec<(usize,usize)>> = HashMap::new();
+    for (c, contour) in contours.iter().enumerate() {
+        for i in 0..num_segments(contour) {
+            let (a, b) = segment(contour, i);
+            let (x0, y0) = cell(f64::min(a.x, b.x), f64::min(a.y, b.y));
+            let (x1, y1) = cell(f64::max(a.x, b.x), f64::max(a.y, b.y));
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    grid.entry((x,y)).or_insert(Vec::new()).push((c,i));
+                }
+            }
+        }
+    }
+
+    let mut tested: HashSet<(usize,usize,usize,usize)> = HashSet::new();
+    let mut intersections = Vec::new();
+    for segments in grid.values() {
+        for (k, a) in segments.iter().enumerate() {
+            for b in segments.iter().skip(k+1) {
+                let (a, b) = if a < b { (a, b) } else { (b, a) };
+                // Consecutive segments of the same contour share a point. On closed contours,
+                // so do the first and the last.
+                if a.0 == b.0 && (b.1 == a.1 + 1 || (a.1 == 0 && b.1 + 1 == num_segments(&contours[a.0]) && contours[a.0].closed)) { continue }
+                if !tested.insert((a.0, a.1, b.0, b.1)) { continue }
+
+                let (a0, a1) = segment(&contours[a.0], a.1);
+                let (b0, b1) = segment(&contours[b.0], b.1);
+                if let Some(location) = segment_intersection(a0, a1, b0, b1) {
+                    intersections.push(Intersection { contours: (a.0, b.0), segments: (a.1, b.1), location, });
+                }
+            }
+        }
+    }
+    intersections
+}
+
+// Returns the number of intersections that remain after the repair.
+pub fn repair_intersections(contours: &mut Vec<Contour>, settings: &MapSettings, post_box: &Sender<ocad::Object>, verbose: bool) -> usize {
+    let module = "CONTOUR".red();
+    let mut intersections = find_intersections(contours);
+    let found = intersections.len();
+
+    for tolerance in REPAIR_TOLERANCES.iter() {
+        if intersections.len() == 0 { break }
+        let mut involved: HashMap<usize,Vec<usize>> = HashMap::new();
+        for i in intersections.iter() {
+            involved.entry(i.contours.0).or_insert(Vec::new()).push(i.segments.0);
+            involved.entry(i.contours.1).or_insert(Vec::new()).push(i.segments.1);
+        }
+        // Last segment first, so that the segments before it keep their index.
+        for (c, mut segments) in involved.into_iter() {
+            segments.sort();
+            segments.dedup();
+            for segment in segments.iter().rev() {
+                contours[c].resimplify_segment(*segment, settings.scaled_area(*tolerance));
+            }
+        }
+        intersections = find_intersections(contours);
+    }
+
+    if verbose && found > 0 {
+        println!("[{}] {} contour intersections found, {} repaired.", &module, found, found - usize::min(found, intersections.len()));
+    }
+
+    for i in intersections.iter() {
+        if verbose {
+            println!("[{}] Contours at {:.1} m and {:.1} m intersect at {:.1}, {:.1}.", &module,
+                contours[i.contours.0].base_elevation, contours[i.contours.1].base_elevation,
+                i.location.x, i.location.y);
+        }
+        let location = Sweref { east: i.location.x, north: i.location.y };
+        post_box.send(ocad::Object::point_object(ocad::CONTOUR_PROBLEM, &location, 0f64)).expect("Unable to send contour problem marker!");
+    }
+
+    intersections.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(x: f64, y: f64) -> Coordinate<f64> {
+        Coordinate { x, y }
+    }
+
+    fn assert_at(p: Option<Coordinate<f64>>, x: f64, y: f64) {
+        let p = p.expect("Segments do not intersect");
+        assert!(f64::abs(p.x - x) < 1e-9 && f64::abs(p.y - y) < 1e-9, "({}, {}) is not ({}, {})", p.x, p.y, x, y);
+    }
+
+    #[test]
+    fn crossing_segments() {
+        assert_at(segment_intersection(&c(0f64, 0f64), &c(2f64, 2f64), &c(0f64, 2f64), &c(2f64, 0f64)), 1f64, 1f64);
+    }
+
+    #[test]
+    fn disjoint_segments() {
+        assert!(segment_intersection(&c(0f64, 0f64), &c(1f64, 1f64), &c(2f64, 0f64), &c(3f64, -1f64)).is_none());
+    }
+
+    #[test]
+    fn segments_touching_at_an_end() {
+        assert_at(segment_intersection(&c(0f64, 0f64), &c(1f64, 0f64), &c(1f64, 0f64), &c(1f64, 1f64)), 1f64, 0f64);
+    }
+
+    #[test]
+    fn parallel_segments() {
+        assert!(segment_intersection(&c(0f64, 0f64), &c(2f64, 0f64), &c(0f64, 1f64), &c(2f64, 1f64)).is_none());
+    }
+
+    #[test]
+    fn collinear_segments() {
+        assert_at(segment_intersection(&c(0f64, 0f64), &c(2f64, 0f64), &c(3f64, 0f64), &c(1f64, 0f64)), 1f64, 0f64);
+        assert!(segment_intersection(&c(0f64, 0f64), &c(1f64, 0f64), &c(2f64, 0f64), &c(3f64, 0f64)).is_none());
+    }
+}
@@ -13,9 +13,11 @@ use ::geo::{Coordinate,LineString};
 use ::geo::algorithm::simplifyvw::SimplifyVW;
 use ::geo::algorithm::euclidean_length::EuclideanLength;
 use std::cmp::Ordering;
-
-const BASE_EQUIDISTANCE: f64 = 5f64;
-const CONTOUR_STEP: f64 = 0.5f64;
+use std::collections::HashMap;
+use super::contour_tree::{ContourTree,Landform};
+use super::contour_validation;
+use super::map_settings::MapSettings;
+use super::feature_index::{FeatureIndex,FeatureKind};
 
 const PENALTY_FOR_ADJACENT_TO_LAKE: f64 = 200f64;
 const BONUS_FOR_ON_CLIFF: f64 = 50f64;
@@ -24,21 +26,179 @@ const PENALTY_FOR_TOO_SHORT_CLOSED_CONTOUR: f64 = 20f64;
 const PENALTY_FOR_EASILY_SIMPLIFIED: f64 = 100f64;
 const LIMIT_FOR_EASILY_SIMPLIFIED: f64 = 0.2f64;
 
+// Penalties per sample point along the contour, for running along or through map features.
+const SAMPLE_SPACING: f64 = 5f64;
+const PENALTY_FOR_ALONG_ROAD: f64 = 200f64;
+const MAX_DISTANCE_TO_ROAD: f64 = 2f64;
+const PENALTY_FOR_ALONG_BUILDING: f64 = 200f64;
+const MAX_DISTANCE_TO_BUILDING: f64 = 1.5f64;
+const PENALTY_FOR_IN_MARSH: f64 = 200f64;
+
+// Area at 1:15000.
+const SIMPLIFICATION_TOLERANCE: f64 = 5f64;
+
+// Local displacement of contour sections away from the globally chosen offset, as a
+// fraction of the contour interval. Sections are about SECTION_LENGTH along the contour
+// at 1:15000, and displacements are compared between sections within SECTION_SIZE cells.
+const MAX_LOCAL_DISPLACEMENT: f64 = 0.3f64;
+const SECTION_LENGTH: f64 = 100f64;
+const SECTION_SIZE: f64 = 100f64;
+const MAX_HORIZONTAL_DISPLACEMENT: f64 = 15f64;
+const MIN_GRADIENT: f64 = 0.01f64;
+const DISPLACEMENT_ITERATIONS: usize = 3;
+const PENALTY_PER_METER_OF_DISPLACEMENT: f64 = 400f64;
+const PENALTY_FOR_INCONSISTENT_DISPLACEMENT: f64 = 800f64;
+
 #[derive(Debug)]
 pub struct Contour {
-    linestring: LineString<f64>,
+    pub linestring: LineString<f64>,
     triangles: Vec<usize>,
-    closed: bool,
+    pub closed: bool,
+    original: LineString<f64>,
     original_length: f64,
-    base_elevation: f64,
+    pub base_elevation: f64,
+    // True if the terrain rises to the left when following the contour.
+    pub uphill_on_left: bool,
 }
 
 fn coord2_to_sweref(c: &Coord2) -> Sweref {
     Sweref { east: c.x(), north: c.y() }
 }
 
+// Score of one triangle that a contour runs through.
+fn triangle_score(t: usize, dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>) -> f64 {
+    let mut score = 0f64;
+    for i in 0..3 {
+        let o = dtm.opposite(t*3 + i);
+        if o != EMPTY && dtm.terrain[o/3] == Terrain::Lake {
+            score = score - PENALTY_FOR_ADJACENT_TO_LAKE;
+        }
+    }
+    if dtm.terrain[t] == Terrain::Cliff { 
+        score = score + BONUS_FOR_ON_CLIFF;
+    }
+
+    let f = (normals[t][Z_NORMAL] - 1f64)*2.0 - 1.0;
+    score + f*f*f*2000.0
+}
+
+// Contours should not follow roads or building walls, nor run through marshes.
+fn sample_score(p: &Sweref, features: &FeatureIndex) -> f64 {
+    let mut score = 0f64;
+    if features.is_near(FeatureKind::Road, p, MAX_DISTANCE_TO_ROAD) {
+        score = score - PENALTY_FOR_ALONG_ROAD;
+    }
+    if features.is_near(FeatureKind::Building, p, MAX_DISTANCE_TO_BUILDING) {
+        score = score - PENALTY_FOR_ALONG_BUILDING;
+    }
+    if features.is_inside(FeatureKind::Marsh, p) {
+        score = score - PENALTY_FOR_IN_MARSH;
+    }
+    score
+}
+
+// Points along a line, at most spacing apart.
+fn sample_points(line: &[Coordinate<f64>], spacing: f64) -> Vec<Sweref> {
+    let mut points = Vec::new();
+    for s in line.windows(2) {
+        let dx = s[1].x - s[0].x;
+        let dy = s[1].y - s[0].y;
+        let n = f64::ceil(f64::sqrt(dx*dx + dy*dy) / spacing) as usize;
+        for i in 0..n {
+            let f = (i as f64) / (n as f64);
+            points.push(Sweref { east: s[0].x + f*dx, north: s[0].y + f*dy });
+        }
+    }
+    if let Some(last) = line.last() {
+        points.push(Sweref { east: last.x, north: last.y });
+    }
+    points
+}
+
+// Distance along a line to each of its points, and the total length. A closed line
+// returns to its first point.
+fn distances_along(line: &[Coordinate<f64>], closed: bool) -> (Vec<f64>, f64) {
+    let mut distances = Vec::with_capacity(line.len());
+    let mut s = 0f64;
+    for (i, p) in line.iter().enumerate() {
+        if i > 0 {
+            s = s + f64::sqrt((p.x - line[i-1].x)*(p.x - line[i-1].x) + (p.y - line[i-1].y)*(p.y - line[i-1].y));
+        }
+        distances.push(s);
+    }
+    if closed && line.len() > 1 {
+        let (first, last) = (line[0], line[line.len()-1]);
+        s = s + f64::sqrt((first.x - last.x)*(first.x - last.x) + (first.y - last.y)*(first.y - last.y));
+    }
+    (distances, s)
+}
+
+// Moves a point across the slope until it is at elevation z, and returns it with the triangle
+// it ends up in. None where the ground is too flat, or the point would move too far.
+fn move_to_elevation(p: &Coordinate<f64>, z: f64, dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, hint: &mut usize) -> Option<(Coordinate<f64>,usize)> {
+    let mut q = Point3D { x: p.x, y: p.y, z };
+    let mut triangle = *hint;
+    for _ in 0..DISPLACEMENT_ITERATIONS {
+        triangle = dtm.triangle_containing_point(&q, triangle)?;
+        if dtm.exterior[triangle] { return None }
+        let dz = z - dtm.z_coordinate_in_triangle(&q, triangle);
+        if f64::abs(dz) < 1e-6 { break }
+        let n = normals[triangle];
+        if n[Z_NORMAL] == 0f64 { return None }
+        // Gradient of the triangle's plane.
+        let (gx, gy) = (-n[0] / n[Z_NORMAL], -n[1] / n[Z_NORMAL]);
+        let g2 = gx*gx + gy*gy;
+        if g2 < MIN_GRADIENT*MIN_GRADIENT { return None }
+        q.x = q.x + dz*gx/g2;
+        q.y = q.y + dz*gy/g2;
+    }
+    triangle = dtm.triangle_containing_point(&q, triangle)?;
+    let (dx, dy) = (q.x - p.x, q.y - p.y);
+    if dx*dx + dy*dy > MAX_HORIZONTAL_DISPLACEMENT*MAX_HORIZONTAL_DISPLACEMENT { return None }
+    *hint = triangle;
+    Some((Coordinate { x: q.x, y: q.y }, triangle))
+}
+
+// Displacement at a distance along a contour, blending linearly between the middles of the
+// sections. Closed contours wrap around.
+fn displacement_at(s: f64, displacements: &Vec<f64>, section_length: f64, closed: bool) -> f64 {
+    let n = displacements.len();
+    let x = s / section_length - 0.5f64;
+    let k = x.floor();
+    let f = x - k;
+    if closed {
+        let a = displacements[(k as i64).rem_euclid(n as i64) as usize];
+        let b = displacements[(k as i64 + 1).rem_euclid(n as i64) as usize];
+        a + f*(b - a)
+    } else if x <= 0f64 {
+        displacements[0]
+    } else if k as usize + 1 >= n {
+        displacements[n-1]
+    } else {
+        let k = k as usize;
+        displacements[k] + f*(displacements[k+1] - displacements[k])
+    }
+}
+
+// The contour score, per triangle, for a section of a contour with the triangle under
+// each point. A closed contour that is a single section may also be too short.
+fn section_score(section: &Vec<(Coordinate<f64>,usize)>, whole_closed_contour: bool,
+    dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, features: &FeatureIndex, settings: &MapSettings) -> f64 {
+
+    let mut triangles: Vec<usize> = section.iter().map(|p| p.1).collect();
+    triangles.dedup();
+    let line: Vec<Coordinate<f64>> = section.iter().map(|p| p.0).collect();
+
+    let mut score = triangles.iter().map(|t| triangle_score(*t, dtm, normals)).sum::<f64>();
+    score = score + sample_points(&line, SAMPLE_SPACING).iter().map(|p| sample_score(p, features)).sum::<f64>();
+    if whole_closed_contour && distances_along(&line, true).1 < settings.scaled_length(DESIRED_LENGTH_FOR_CLOSED_CONTOUR) {
+        score = score - PENALTY_FOR_TOO_SHORT_CLOSED_CONTOUR;
+    }
+    score / (usize::max(triangles.len(), 1) as f64)
+}
+
 impl Contour {
-    pub fn score(&self, dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>) -> f64 {
+    pub fn score(&self, dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, features: &FeatureIndex, settings: &MapSettings) -> f64 {
         let mut score = 0f64;
         let length = self.linestring.euclidean_length();
 
@@ -48,28 +208,18 @@ impl Contour {
         }
 
         for t in self.triangles.iter() {
-            for i in 0..3 {
-                let o = dtm.opposite(t*3 + i);
-                if o != EMPTY && dtm.terrain[o/3] == Terrain::Lake {
-                    score = score - PENALTY_FOR_ADJACENT_TO_LAKE;
-                }
-            }
-            if dtm.terrain[*t] == Terrain::Cliff { 
-                score = score + BONUS_FOR_ON_CLIFF;
-            }
-
-            let f = (normals[*t][Z_NORMAL] - 1f64)*2.0 - 1.0;
-            score = score + f*f*f*2000.0;
+            score = score + triangle_score(*t, dtm, normals);
         }
 
-        if self.closed && length < DESIRED_LENGTH_FOR_CLOSED_CONTOUR {
+        if self.closed && length < settings.scaled_length(DESIRED_LENGTH_FOR_CLOSED_CONTOUR) {
             score = score - PENALTY_FOR_TOO_SHORT_CLOSED_CONTOUR;
         }
 
         score = score + length;
-        
 
-        
+        for p in sample_points(&self.linestring.0, SAMPLE_SPACING).iter() {
+            score = score + sample_score(p, features);
+        }
 
 
         // TODO: Gradients - the most important aspect!
@@ -83,6 +233,72 @@ impl Contour {
         score
     }
 
+    // Simplifies the traced contour again, with a different tolerance. A tolerance of zero
+    // restores the traced points.
+    pub fn resimplify(&mut self, tolerance: f64) {
+        self.linestring = if tolerance > 0f64 { self.original.simplifyvw(&tolerance) } else { self.original.clone() };
+    }
+
+    // Position of each point of the simplified contour among the traced points. Simplification
+    // only leaves traced points out, so they are found in order.
+    fn traced_indices(&self) -> Option<Vec<usize>> {
+        let mut indices = Vec::with_capacity(self.linestring.0.len());
+        let mut j = 0;
+        for p in self.linestring.0.iter() {
+            while j < self.original.0.len() && self.original.0[j] != *p { j = j + 1; }
+            if j == self.original.0.len() { return None }
+            indices.push(j);
+            j = j + 1;
+        }
+        Some(indices)
+    }
+
+    // Simplifies the traced points along one segment of the simplified contour again, with a
+    // different tolerance. The ends of the segment and the rest of the contour stay as they are.
+    // The last segment of a closed contour runs back to the first point.
+    pub fn resimplify_segment(&mut self, segment: usize, tolerance: f64) {
+        let indices = match self.traced_indices() {
+            Some(indices) => indices,
+            None => { self.resimplify(tolerance); return },
+        };
+        let (start, end) = (indices[segment], indices[(segment + 1) % indices.len()]);
+        if start == end { return }
+        let traced: Vec<Coordinate<f64>> = if end > start {
+            self.original.0[start..=end].to_vec()
+        } else {
+            self.original.0[start..].iter().chain(self.original.0[..=end].iter()).cloned().collect()
+        };
+        let traced = LineString::from(traced);
+        let section = if tolerance > 0f64 { traced.simplifyvw(&tolerance) } else { traced };
+        let inner = section.0[1..section.0.len()-1].to_vec();
+        self.linestring.0.splice(segment+1..segment+1, inner);
+    }
+
+    // Moves the traced points up or down by the displacement of their section, blending
+    // between sections, and simplifies again. Points that cannot be moved stay in place.
+    fn displace(&mut self, displacements: &Vec<f64>, dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, tolerance: f64) {
+        let (distances, total) = distances_along(&self.original.0, self.closed);
+        let section_length = total / (displacements.len() as f64);
+        let mut hint = self.triangles[0];
+        let mut points = Vec::with_capacity(distances.len());
+        let mut triangles = Vec::with_capacity(distances.len());
+        for (p, s) in self.original.0.iter().zip(distances.iter()) {
+            let z = self.base_elevation + displacement_at(*s, displacements, section_length, self.closed);
+            match move_to_elevation(p, z, dtm, normals, &mut hint) {
+                Some((q, t)) => {
+                    points.push(q);
+                    if triangles.last() != Some(&t) { triangles.push(t); }
+                },
+                None => points.push(*p),
+            }
+        }
+        if triangles.len() == 0 { return }
+
+        self.original = LineString::from(points);
+        self.original_length = self.original.euclidean_length();
+        self.triangles = triangles;
+        self.resimplify(tolerance);
+    }
 
     pub fn ocad_object(&self) -> ocad::Object {
         let segments = self.linestring
@@ -97,13 +313,14 @@ impl Contour {
             object_type: ocad::ObjectType::Line(false),
             symbol_number: 101000,
             segments,
+            height: None,
         }
     }
 
-    pub fn bezier_ocad_object(&self) -> ocad::Object {
+    pub fn bezier_ocad_object(&self, settings: &MapSettings) -> ocad::Object {
         let mut segments: Vec<ocad::Segment> = Vec::new();
         let coords: Vec<Coord2> = self.linestring.points_iter().map(|p| Coord2(p.x(),p.y())).collect();
-        let beziers: Vec<bezier::Curve<Coord2>> = bezier::fit_curve(&coords[..], 5.0).expect("Unable to create bezier");
+        let beziers: Vec<bezier::Curve<Coord2>> = bezier::fit_curve(&coords[..], settings.scaled_length(5.0)).expect("Unable to create bezier");
 
         if beziers.len() > 0 {
             segments.push(ocad::Segment::Move(coord2_to_sweref(&beziers[0].start_point)));
@@ -120,6 +337,7 @@ impl Contour {
             object_type: ocad::ObjectType::Line(false),
             symbol_number: 101000,
             segments,
+            height: None,
         }
     }
 }
@@ -127,7 +345,7 @@ impl Contour {
 type Position = (Halfedge,Point3D);
 
 impl Contour {
-    fn from_dtm(dtm: &DigitalTerrainModel, z_limits: &Vec<(f64,f64)>, z: f64) -> Vec<Contour> {
+    fn from_dtm(dtm: &DigitalTerrainModel, z_limits: &Vec<(f64,f64)>, z: f64, tolerance: f64) -> Vec<Contour> {
         let intersections_with_triangle = |t: usize| -> Vec<Position> {
             ((t*3)..(t*3+3)).filter_map(|i| {
                 let a = dtm.points[dtm.vertices[i]];
@@ -213,39 +431,154 @@ impl Contour {
 
             let closed = !reached_first_end_of_open_contour;
             let npoints = points.len();
-            let original_linestring = LineString::from(points);
-            let original_length = original_linestring.euclidean_length();
-            let linestring = original_linestring.simplifyvw(&5.0);
-            if npoints >= 2 && original_length > 0f64 {
-                contours.push(Contour { linestring, triangles, closed, original_length, base_elevation: z, })
+            if npoints < 2 { continue }
+
+            // Look at the triangle under the middle segment. Its corners above z are all
+            // on the same side of the contour.
+            let uphill_on_left = {
+                let a = points[npoints/2 - 1];
+                let b = points[npoints/2];
+                let a3 = Point3D { x: a.x, y: a.y, z, };
+                let b3 = Point3D { x: b.x, y: b.y, z, };
+                let middle = Point3D { x: (a.x + b.x)*0.5, y: (a.y + b.y)*0.5, z, };
+                match dtm.triangle_containing_point(&middle, triangles[npoints/2]) {
+                    Some(t) => (0..3)
+                        .map(|i| dtm.points[dtm.vertices[t*3 + i]])
+                        .filter(|p| p.z > z)
+                        .next()
+                        .map(|p| p.to_the_left_of(&a3, &b3))
+                        .unwrap_or(false),
+                    None => false,
+                }
+            };
+
+            let original = LineString::from(points);
+            let original_length = original.euclidean_length();
+            let linestring = original.simplifyvw(&tolerance);
+            if original_length > 0f64 {
+                contours.push(Contour { linestring, triangles, closed, original, original_length, base_elevation: z, uphill_on_left, })
             }
         }
         contours
     }
 }
 
-pub fn create_contours_from_base_z(dtm: Arc<DigitalTerrainModel>, normals: Arc<Vec<[f64;3]>>,
-    min_z: f64, max_z: f64, offset: f64,
+pub fn create_contours_from_base_z(dtm: Arc<DigitalTerrainModel>, normals: Arc<Vec<[f64;3]>>, features: Arc<FeatureIndex>,
+    min_z: f64, max_z: f64, offset: f64, settings: MapSettings,
     post_box: Sender<(f64,f64,Vec<Contour>)>) {
 
     let mut contours: Vec<Contour> = Vec::new();
     let mut z = min_z + offset;
 
     let z_limits = dtm.z_limits();
+    let tolerance = settings.scaled_area(SIMPLIFICATION_TOLERANCE);
 
     while z < max_z {
-        contours.append(&mut Contour::from_dtm(&dtm.deref(), &z_limits, z));
-        z = z + BASE_EQUIDISTANCE;
+        contours.append(&mut Contour::from_dtm(&dtm.deref(), &z_limits, z, tolerance));
+        z = z + settings.equidistance;
     }
     let score = contours.iter()
-        .map(|c| c.score(&dtm.deref(), &normals.deref())).sum::<f64>();
+        .map(|c| c.score(&dtm.deref(), &normals.deref(), &features.deref(), &settings)).sum::<f64>();
 
     post_box.send((offset, score, contours)).expect("Unable to send contours to collator!");
 }
 
 
-pub fn create_contours(dtm: DigitalTerrainModel, 
-    min_z: f64, max_z: f64, z_resolution: f64,
+// Keeps track of the displacements chosen so far, per section of the map. Contours at
+// neighbouring levels in the same area should move together, or the spacing between them
+// no longer reflects the slope.
+struct DisplacementField {
+    displacements: HashMap<(i64,i64),Vec<f64>>,
+}
+
+impl DisplacementField {
+    fn cell(p: &Coordinate<f64>) -> (i64,i64) {
+        ((p.x / SECTION_SIZE).floor() as i64, (p.y / SECTION_SIZE).floor() as i64)
+    }
+
+    fn expected_displacement(&self, cell: (i64,i64)) -> f64 {
+        let nearby: Vec<f64> = (-1..=1)
+            .flat_map(|dx| (-1..=1).map(move |dy| (cell.0 + dx, cell.1 + dy)))
+            .filter_map(|c| self.displacements.get(&c))
+            .flatten()
+            .cloned()
+            .collect();
+        if nearby.len() == 0 { 0f64 } else { nearby.iter().sum::<f64>() / (nearby.len() as f64) }
+    }
+
+    fn record(&mut self, cell: (i64,i64), displacement: f64) {
+        self.displacements.entry(cell).or_insert(Vec::new()).push(displacement);
+    }
+}
+
+// Splits each contour into sections, and moves each section up or down within a fraction of
+// the contour interval where that scores better locally. Contours are handled from the lowest,
+// so that each section can follow what was chosen for the sections around it. Returns the
+// number of sections, and how many of them were displaced.
+fn displace_contours_locally(contours: &mut Vec<Contour>,
+    dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, features: &FeatureIndex, settings: &MapSettings) -> (usize, usize) {
+
+    contours.sort_by(|a,b| if a.base_elevation < b.base_elevation { Ordering::Less } else { Ordering::Greater });
+
+    let max_steps = (MAX_LOCAL_DISPLACEMENT * settings.equidistance / settings.contour_step()).round() as i64;
+    let tolerance = settings.scaled_area(SIMPLIFICATION_TOLERANCE);
+    let mut field = DisplacementField { displacements: HashMap::new() };
+    let mut num_sections = 0;
+    let mut num_displaced = 0;
+
+    for c in contours.iter_mut() {
+        let (distances, total) = distances_along(&c.original.0, c.closed);
+        let n = usize::max(1, (total / settings.scaled_length(SECTION_LENGTH)).round() as usize);
+        let section_length = total / (n as f64);
+        let mut displacements = vec![0f64; n];
+
+        for k in 0..n {
+            let section: Vec<Coordinate<f64>> = c.original.0.iter()
+                .zip(distances.iter())
+                .filter(|(_, s)| usize::min(n - 1, (**s / section_length) as usize) == k)
+                .map(|(p, _)| *p)
+                .collect();
+            if section.len() < 2 { continue }
+            num_sections = num_sections + 1;
+
+            let cell = DisplacementField::cell(&section[section.len()/2]);
+            let expected = field.expected_displacement(cell);
+            let mut best: (f64,f64) = (f64::MIN, 0f64);
+            for step in -max_steps..=max_steps {
+                let displacement = (step as f64) * settings.contour_step();
+                let mut hint = c.triangles[0];
+                let displaced: Option<Vec<(Coordinate<f64>,usize)>> = section.iter()
+                    .map(|p| move_to_elevation(p, c.base_elevation + displacement, dtm, normals, &mut hint))
+                    .collect();
+                let displaced = match displaced {
+                    Some(d) => d,
+                    None => continue,
+                };
+                let score = section_score(&displaced, c.closed && n == 1, dtm, normals, features, settings)
+                    - PENALTY_PER_METER_OF_DISPLACEMENT * f64::abs(displacement)
+                    - PENALTY_FOR_INCONSISTENT_DISPLACEMENT * f64::abs(displacement - expected);
+                if score > best.0 {
+                    best = (score, displacement);
+                }
+            }
+
+            displacements[k] = best.1;
+            field.record(cell, best.1);
+            if best.1 != 0f64 {
+                num_displaced = num_displaced + 1;
+            }
+        }
+
+        if displacements.iter().any(|d| *d != 0f64) {
+            c.displace(&displacements, dtm, normals, tolerance);
+        }
+    }
+
+    (num_sections, num_displaced)
+}
+
+pub fn create_contours(dtm: DigitalTerrainModel, features: Arc<FeatureIndex>,
+    min_z: f64, max_z: f64, z_resolution: f64, settings: MapSettings,
     post_box: Sender<ocad::Object>, verbose: bool) {
     let module = "CONTOUR".red();
 
@@ -255,14 +588,15 @@ pub fn create_contours(dtm: DigitalTerrainModel,
     let normals_rc = Arc::new(normals);
     let mut offset: f64 = z_resolution*0.5;
     let mut num_contour_levels = 0;
-    while offset < BASE_EQUIDISTANCE - CONTOUR_STEP*0.5 {
+    while offset < settings.equidistance - settings.contour_step()*0.5 {
         let d = dtm_rc.clone();
         let n = normals_rc.clone();
+        let f = features.clone();
         let collector_box = tx.clone();
         thread::spawn(move || {
-            create_contours_from_base_z(d, n, min_z, max_z, offset, collector_box);            
+            create_contours_from_base_z(d, n, f, min_z, max_z, offset, settings, collector_box);            
         }); 
-        offset = offset + CONTOUR_STEP;
+        offset = offset + settings.contour_step();
         num_contour_levels = num_contour_levels + 1;
     }
 
@@ -273,8 +607,8 @@ pub fn create_contours(dtm: DigitalTerrainModel,
     }
 
     if verbose {
-        println!("[{}] Created {} contours at 0.5 m intervals.", &module, 
-            contour_sets.iter().map(|s| s.2.len()).sum::<usize>());
+        println!("[{}] Created {} contours at {} m intervals.", &module, 
+            contour_sets.iter().map(|s| s.2.len()).sum::<usize>(), settings.contour_step());
     }
 
     contour_sets.sort_by(|a,b| if a.1 > b.1 { Ordering::Less } else { Ordering::Greater });
@@ -282,15 +616,46 @@ pub fn create_contours(dtm: DigitalTerrainModel,
     //     println!("{} {} {}", c.0, c.1, c.2.len());
     // }
 
-    let (level, _, contours) = &contour_sets[0];
-    println!("Choosing {}, with {} contours.", level, contours.len());
+    let level = contour_sets[0].0;
+    println!("Choosing {}, with {} contours.", level, contour_sets[0].2.len());
+    let mut contours = contour_sets.swap_remove(0).2;
+    let (num_sections, num_displaced) = displace_contours_locally(&mut contours, dtm_rc.deref(), normals_rc.deref(), features.deref(), &settings);
+    let problems = contour_validation::repair_intersections(&mut contours, &settings, &post_box, verbose);
+    // Contours nested against their elevations are marked like intersections that could not be
+    // repaired.
+    let tree = ContourTree::build(&contours, &dtm_rc.bounds);
+    let inconsistent = tree.inconsistencies(settings.equidistance * (1f64 + 2f64*MAX_LOCAL_DISPLACEMENT));
+    for n in inconsistent.iter() {
+        let (x, y) = tree.probe(*n);
+        post_box.send(ocad::Object::point_object(ocad::CONTOUR_PROBLEM, &Sweref { east: x, north: y }, 0f64))
+            .expect("Unable to send contour problem marker!");
+    }
+    if verbose {
+        println!("[{}] {} of {} contour sections displaced locally, up to {} m.", &module, num_displaced, num_sections, MAX_LOCAL_DISPLACEMENT * settings.equidistance);
+        println!("[{}] {} knolls and {} depressions enclosed by contours.", &module,
+            tree.nodes_with_landform(Landform::Knoll).len(),
+            tree.nodes_with_landform(Landform::Depression).len());
+        println!("[{}] {} outermost contours, nested up to {} levels deep.", &module,
+            tree.roots.len(), (0..tree.nodes.len()).map(|n| tree.depth(n)).max().unwrap_or(0));
+        if inconsistent.len() > 0 {
+            println!("[{}] {} contours are nested inconsistently.", &module, inconsistent.len());
+        }
+        for n in inconsistent.iter() {
+            let c = &contours[tree.nodes[*n].contour];
+            println!("[{}] Contour at {:.1} m from {:.1}, {:.1} is nested inconsistently.", &module,
+                c.base_elevation, c.linestring.0[0].x, c.linestring.0[0].y);
+        }
+        if problems > 0 {
+            println!("[{}] {} contour intersections could not be repaired.", &module, problems);
+        }
+    }
     let mut total_contours = 0;
 
     for c in contours.iter() {
             if let Some(object) = match c.linestring.num_coords() {
                 0..=3 => None,
                 4..=100000 => Some(c.ocad_object()),
-                _ => Some(c.bezier_ocad_object()),
+                _ => Some(c.bezier_ocad_object(&settings)),
             } { 
                 post_box.send(object).expect("Unable to send contour!");   
                 total_contours = total_contours+1;
@@ -0,0 +1,116 @@
+use super::ocad;
+use super::dtm::{DigitalTerrainModel,Terrain,WaterLevel};
+use super::boundary::{Region,regions_within};
+use crate::geometry::Point3D;
+use std::sync::mpsc::Sender;
+use colored::*;
+
+// Detectors read the DTM without changing it, so that they can run at the same time. Each
+// returns what it found, and the findings are merged into the DTM afterwards.
+pub struct Detection {
+    // Triangles covered by the feature, labelled with terrain unless it is Unclassified.
+    pub terrain: Terrain,
+    pub triangles: Vec<usize>,
+    // New z values for points, e.g. for levelling a lake.
+    pub elevations: Vec<(usize,f64)>,
+    pub water_level: Option<WaterLevel>,
+    pub objects: Vec<ocad::Object>,
+    // Makes the objects again for a part of the triangles, when the rest were already claimed.
+    // Detections without it are dropped if any of their triangles were claimed.
+    pub rebuild: Option<Box<dyn Fn(&DigitalTerrainModel, &Region) -> Vec<ocad::Object> + Send>>,
+}
+
+// A detection is dropped if more than this fraction of its triangles were already claimed.
+const MAX_OVERLAP: f64 = 0.5f64;
+
+// Lower comes first, and wins conflicts.
+fn priority(terrain: Terrain) -> usize {
+    match terrain {
+        Terrain::Lake => 0,
+        Terrain::Cliff => 1,
+        Terrain::Marsh => 2,
+        Terrain::Unclassified => 3,
+    }
+}
+
+fn is_on_claimed_ground(dtm: &DigitalTerrainModel, claimed: &Vec<bool>, object: &ocad::Object) -> bool {
+    let mut hint = 0usize;
+    object.segments.iter()
+        .flat_map(|s| match s {
+            ocad::Segment::Move(p) | ocad::Segment::Line(p) => vec![*p],
+            ocad::Segment::Bezier(_, _, p) => vec![*p],
+        })
+        .any(|p| match dtm.triangle_containing_point(&Point3D { x: p.east, y: p.north, z: 0f64 }, hint) {
+            Some(t) => { hint = t; claimed[t] },
+            None => false,
+        })
+}
+
+pub fn merge(dtm: &mut DigitalTerrainModel, detections: Vec<Vec<Detection>>,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let mut detections: Vec<Detection> = detections.into_iter().flatten().collect();
+    detections.sort_by_key(|d| priority(d.terrain));
+
+    // Triangles labelled by an earlier merge are also taken, and so are their corners.
+    let mut claimed: Vec<bool> = dtm.terrain.iter().map(|t| *t != Terrain::Unclassified).collect();
+    let mut claimed_vertex = vec![false; dtm.points.len()];
+    for t in (0..dtm.num_triangles).filter(|t| claimed[*t]) {
+        for h in t*3..t*3+3 { claimed_vertex[dtm.vertices[h]] = true; }
+    }
+    let mut num_dropped = 0;
+    let mut num_clipped = 0;
+    for detection in detections.into_iter() {
+        let overlap = detection.triangles.iter().filter(|t| claimed[**t]).count();
+        let objects: Vec<ocad::Object> = if detection.terrain == Terrain::Unclassified {
+            // Features that do not label the terrain, like boulders, sit on unclaimed ground.
+            let objects: Vec<ocad::Object> = detection.objects.into_iter()
+                .filter(|o| !is_on_claimed_ground(dtm, &claimed, o))
+                .collect();
+            if objects.len() == 0 {
+                num_dropped = num_dropped + 1;
+                continue;
+            }
+            objects
+        } else if overlap == 0 {
+            detection.objects
+        } else {
+            match detection.rebuild {
+                Some(ref rebuild) if (overlap as f64) <= MAX_OVERLAP * (detection.triangles.len() as f64) => {
+                    let unclaimed: Vec<usize> = detection.triangles.iter().filter(|t| !claimed[**t]).cloned().collect();
+                    num_clipped = num_clipped + 1;
+                    regions_within(dtm, &unclaimed).iter().flat_map(|r| rebuild(dtm, r)).collect()
+                },
+                _ => {
+                    num_dropped = num_dropped + 1;
+                    continue;
+                },
+            }
+        };
+
+        // Points that belong to an earlier detection keep the elevation it gave them.
+        for (point, z) in detection.elevations.iter() {
+            if claimed_vertex[*point] { continue }
+            dtm.points[*point].z = *z;
+        }
+        if detection.terrain != Terrain::Unclassified {
+            for t in detection.triangles.iter() {
+                if claimed[*t] { continue }
+                claimed[*t] = true;
+                dtm.terrain[*t] = detection.terrain;
+                for h in t*3..t*3+3 { claimed_vertex[dtm.vertices[h]] = true; }
+            }
+        }
+        if let Some(water_level) = detection.water_level {
+            dtm.water_levels.push(water_level);
+        }
+        for object in objects.into_iter() {
+            post_box.send(object).expect("Unable to send detected object!");
+        }
+    }
+
+    if verbose && num_dropped + num_clipped > 0 {
+        let module = "DETECT".cyan();
+        println!("[{}] {} detections dropped and {} cut back in favour of overlapping ones.", &module, num_dropped, num_clipped);
+    }
+}
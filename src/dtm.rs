@@ -31,11 +31,12 @@ impl TriangleWalk for Halfedge {
     }    
 }
 
-#[derive(Clone,PartialEq)]
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum Terrain {
     Unclassified,
     Lake,
     Cliff,
+    Marsh,
 }
 
 #[derive(Clone)]
@@ -52,6 +53,14 @@ pub struct DigitalTerrainModel {
     pub exterior: Vec<bool>,
     pub terrain: Vec<Terrain>,
     pub bounds: Bounds,
+    pub water_levels: Vec<WaterLevel>,
+}
+
+// The surface of a lake. The location is a point in the lake, at the water level.
+#[derive(Clone,Debug)]
+pub struct WaterLevel {
+    pub location: Point3D,
+    pub area: f64,
 }
 
 impl DigitalTerrainModel {
@@ -86,7 +95,7 @@ impl DigitalTerrainModel {
     pub fn create(records: &Vec<PointDataRecord>, point_converter: &PointConverter) -> DigitalTerrainModel {
 
         let ground_points: Vec<Point3D> = records.iter()
-            .filter(|record| record.classification == 2)
+            .filter(|record| record.classification() == 2)
             .map(|record| point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]))
             .collect();
 
@@ -133,7 +142,8 @@ impl DigitalTerrainModel {
             halfedges: triangulation.halfedges.clone(),
             num_triangles: num_triangles,
             terrain: vec![Terrain::Unclassified; num_triangles],
-            exterior: exteriors, areas: areas, bounds
+            exterior: exteriors, areas: areas, bounds,
+            water_levels: Vec::new(),
         }
     }
 
@@ -162,6 +172,21 @@ impl DigitalTerrainModel {
             }).collect()
     }
 
+    // For each point, the points it shares an edge with, and the triangles it is a corner of.
+    pub fn vertex_neighbours(&self) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+        let mut neighbours = vec![Vec::new(); self.points.len()];
+        let mut triangles = vec![Vec::new(); self.points.len()];
+        for h in 0..self.vertices.len() {
+            let a = self.vertices[h];
+            let b = self.vertices[h.next()];
+            neighbours[a].push(b);
+            // Edges on the convex hull have no opposite halfedge to add them from the other end.
+            if self.halfedges[h] == EMPTY { neighbours[b].push(a); }
+            triangles[a].push(h / 3);
+        }
+        (neighbours, triangles)
+    }
+
     fn next_triangle_toward_point(&self, point: &Point3D, triangle: usize) -> Option<usize> {
         for edge in 0..3 {
             let p0 = &self.points[self.vertices[triangle*3 + edge]];
@@ -190,29 +215,78 @@ impl DigitalTerrainModel {
     pub fn z_coordinate_at_xy(&self, point: &Point3D) -> f64 {
         match self.triangle_containing_point(point, 0usize) {
             None => (self.bounds.upper.z + self.bounds.lower.z) * 0.5f64,
-            Some(triangle) => {
-                let p0 = self.points[self.vertices[triangle*3+0]];
-                let p1 = self.points[self.vertices[triangle*3+1]];
-                let p2 = self.points[self.vertices[triangle*3+2]];
+            Some(triangle) => self.z_coordinate_in_triangle(point, triangle),
+        }
+    }
 
-                let v = Point3D { x: p1.x-p0.x, y: p1.y-p0.y, z: p1.z-p0.z };
-                let u = Point3D { x: p2.x-p0.x, y: p2.y-p0.y, z: p2.z-p0.z };
-                let nx = u.y*v.z - u.z*v.y;
-                let ny = u.z*v.x - u.x*v.z;
-                let nz = u.x*v.y - u.y*v.x;
-                let l = f64::sqrt(nx*nx + ny*ny + nz*nz);
-                let n = [nx/l, ny/l, nz/l];
-
-                if n[2] == 0f64 {
-                    // Vertical triangle
-                    (p0.z + p1.z + p2.z) * 0.33f64
-                } else {
-                    // d = n[0]*p0.x + n[1]*p0.y + n[2]*p0.z
-                    // d = n[0]*point.x + n[1]*point.y + n[2]*point.z
-                    (n[0]*p0.x + n[1]*p0.y + n[2]*p0.z - n[0]*point.x - n[1]*point.y) / n[2]
-                }
-            }
+    pub fn z_coordinate_in_triangle(&self, point: &Point3D, triangle: usize) -> f64 {
+        let p0 = self.points[self.vertices[triangle*3+0]];
+        let p1 = self.points[self.vertices[triangle*3+1]];
+        let p2 = self.points[self.vertices[triangle*3+2]];
+
+        let v = Point3D { x: p1.x-p0.x, y: p1.y-p0.y, z: p1.z-p0.z };
+        let u = Point3D { x: p2.x-p0.x, y: p2.y-p0.y, z: p2.z-p0.z };
+        let nx = u.y*v.z - u.z*v.y;
+        let ny = u.z*v.x - u.x*v.z;
+        let nz = u.x*v.y - u.y*v.x;
+        let l = f64::sqrt(nx*nx + ny*ny + nz*nz);
+        let n = [nx/l, ny/l, nz/l];
+
+        if n[2] == 0f64 {
+            // Vertical triangle
+            (p0.z + p1.z + p2.z) * 0.33f64
+        } else {
+            // d = n[0]*p0.x + n[1]*p0.y + n[2]*p0.z
+            // d = n[0]*point.x + n[1]*point.y + n[2]*point.z
+            (n[0]*p0.x + n[1]*p0.y + n[2]*p0.z - n[0]*point.x - n[1]*point.y) / n[2]
         }
     }
 
 }
+
+#[cfg(test)]
+impl DigitalTerrainModel {
+    // A grid of unit cells, each split into two clockwise triangles. Cell (i,j) holds triangles
+    // 2*(j*columns + i) and 2*(j*columns + i) + 1. The outer ring of cells is exterior.
+    pub fn grid<F>(columns: usize, rows: usize, elevation: F) -> DigitalTerrainModel
+        where F: Fn(f64,f64) -> f64 {
+        use std::collections::HashMap;
+
+        let vertex = |i: usize, j: usize| j*(columns+1) + i;
+        let points: Vec<Point3D> = (0..(columns+1)*(rows+1))
+            .map(|v| {
+                let (x, y) = ((v % (columns+1)) as f64, (v / (columns+1)) as f64);
+                Point3D { x, y, z: elevation(x, y) }
+            })
+            .collect();
+
+        let mut vertices = Vec::new();
+        let mut exterior = Vec::new();
+        for j in 0..rows {
+            for i in 0..columns {
+                let (a, b, c, d) = (vertex(i, j), vertex(i+1, j), vertex(i+1, j+1), vertex(i, j+1));
+                vertices.extend_from_slice(&[a, d, c, a, c, b]);
+                let edge = i == 0 || j == 0 || i == columns-1 || j == rows-1;
+                exterior.extend_from_slice(&[edge, edge]);
+            }
+        }
+
+        let edges: HashMap<(usize,usize), Halfedge> = (0..vertices.len())
+            .map(|h| ((vertices[h], vertices[h.next()]), h))
+            .collect();
+        let halfedges = (0..vertices.len())
+            .map(|h| *edges.get(&(vertices[h.next()], vertices[h])).unwrap_or(&EMPTY))
+            .collect();
+
+        let num_triangles = vertices.len() / 3;
+        let min_z = points.iter().map(|p| p.z).fold(0./0., f64::min);
+        let max_z = points.iter().map(|p| p.z).fold(0./0., f64::max);
+        DigitalTerrainModel {
+            points, vertices, halfedges, num_triangles,
+            terrain: vec![Terrain::Unclassified; num_triangles],
+            exterior, areas: vec![0.5f64; num_triangles],
+            bounds: Bounds { lower: Point3D { x: 0f64, y: 0f64, z: min_z }, upper: Point3D { x: columns as f64, y: rows as f64, z: max_z } },
+            water_levels: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use super::ocad;
+use super::Sweref;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver,Sender};
+
+// A spatial index over objects that are already on their way to the map, from the
+// pre-existing map data and from the detectors. It answers questions like "is there a
+// road within 2 m of this point" for the contour thread and the detectors.
+
+const CELL_SIZE: f64 = 25f64;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum FeatureKind {
+    Water,
+    Watercourse,
+    Marsh,
+    Cultivated,
+    CultivationBoundary,
+    Road,
+    PowerLine,
+    Residential,
+    Building,
+    Other,
+}
+
+// Areas the map data already describes in full: water, fields, built-up areas and buildings.
+// Detectors of ground cover and paths leave them alone.
+pub const MAPPED_AREAS: [FeatureKind;4] = [FeatureKind::Water, FeatureKind::Cultivated, FeatureKind::Residential, FeatureKind::Building];
+
+impl FeatureKind {
+    pub fn from_symbol(symbol_number: i32) -> FeatureKind {
+        match symbol_number / 1000 {
+            301 | 302 => FeatureKind::Water,
+            304 | 305 | 306 => FeatureKind::Watercourse,
+            307 | 308 | 309 | 310 => FeatureKind::Marsh,
+            412 | 413 => FeatureKind::Cultivated,
+            415 => FeatureKind::CultivationBoundary,
+            501..=509 => FeatureKind::Road,
+            510 | 511 => FeatureKind::PowerLine,
+            520 => FeatureKind::Residential,
+            521 => FeatureKind::Building,
+            _ => FeatureKind::Other,
+        }
+    }
+}
+
+pub struct FeatureIndex {
+    // Line objects, and the outlines of area objects.
+    lines: Vec<(FeatureKind,Vec<Sweref>)>,
+    areas: Vec<(FeatureKind,Vec<Vec<Sweref>>)>,
+    line_cells: HashMap<(i64,i64),Vec<(usize,usize)>>,
+    area_cells: HashMap<(i64,i64),Vec<usize>>,
+}
+
+fn cell(p: &Sweref) -> (i64,i64) {
+    ((p.east / CELL_SIZE).floor() as i64, (p.north / CELL_SIZE).floor() as i64)
+}
+
+pub fn distance_to_segment(p: &Sweref, a: &Sweref, b: &Sweref) -> f64 {
+    let vx = b.east - a.east;
+    let vy = b.north - a.north;
+    let l2 = vx*vx + vy*vy;
+    let t = if l2 > 0f64 { f64::max(0f64, f64::min(1f64, ((p.east - a.east)*vx + (p.north - a.north)*vy) / l2)) } else { 0f64 };
+    let dx = a.east + t*vx - p.east;
+    let dy = a.north + t*vy - p.north;
+    f64::sqrt(dx*dx + dy*dy)
+}
+
+fn ring_contains(ring: &Vec<Sweref>, p: &Sweref) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.north > p.north) != (b.north > p.north) &&
+            p.east < a.east + (p.north - a.north) * (b.east - a.east) / (b.north - a.north) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+impl FeatureIndex {
+    pub fn new() -> FeatureIndex {
+        FeatureIndex { lines: Vec::new(), areas: Vec::new(), line_cells: HashMap::new(), area_cells: HashMap::new(), }
+    }
+
+    fn insert_line(&mut self, kind: FeatureKind, points: Vec<Sweref>) {
+        let index = self.lines.len();
+        for (i, s) in points.windows(2).enumerate() {
+            let (x0, y0) = cell(&Sweref { east: f64::min(s[0].east, s[1].east), north: f64::min(s[0].north, s[1].north) });
+            let (x1, y1) = cell(&Sweref { east: f64::max(s[0].east, s[1].east), north: f64::max(s[0].north, s[1].north) });
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    self.line_cells.entry((x,y)).or_insert(Vec::new()).push((index,i));
+                }
+            }
+        }
+        self.lines.push((kind, points));
+    }
+
+    fn insert_area(&mut self, kind: FeatureKind, rings: Vec<Vec<Sweref>>) {
+        let index = self.areas.len();
+        let points = rings.iter().flatten();
+        let x0 = points.clone().map(|p| p.east).fold(f64::MAX, f64::min);
+        let x1 = points.clone().map(|p| p.east).fold(f64::MIN, f64::max);
+        let y0 = points.clone().map(|p| p.north).fold(f64::MAX, f64::min);
+        let y1 = points.map(|p| p.north).fold(f64::MIN, f64::max);
+        let (x0, y0) = cell(&Sweref { east: x0, north: y0 });
+        let (x1, y1) = cell(&Sweref { east: x1, north: y1 });
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                self.area_cells.entry((x,y)).or_insert(Vec::new()).push(index);
+            }
+        }
+        for ring in rings.iter() {
+            let mut outline = ring.clone();
+            outline.push(ring[0]);
+            self.insert_line(kind, outline);
+        }
+        self.areas.push((kind, rings));
+    }
+
+    pub fn insert(&mut self, object: &ocad::Object) {
+        let kind = FeatureKind::from_symbol(object.symbol_number);
+        if kind == FeatureKind::Other { return }
+
+        // A move starts a new part, or a hole in an area.
+        let mut parts: Vec<Vec<Sweref>> = Vec::new();
+        for segment in object.segments.iter() {
+            match segment {
+                ocad::Segment::Move(p) => parts.push(vec![*p]),
+                ocad::Segment::Line(p) | ocad::Segment::Bezier(_,_,p) => match parts.last_mut() {
+                    Some(part) => part.push(*p),
+                    None => parts.push(vec![*p]),
+                },
+            }
+        }
+        parts.retain(|p| p.len() > 1);
+        if parts.len() == 0 { return }
+
+        match object.object_type {
+            ocad::ObjectType::Area => self.insert_area(kind, parts),
+            ocad::ObjectType::Line(_) => for part in parts.into_iter() { self.insert_line(kind, part) },
+            _ => {},
+        }
+    }
+
+    // Indexes everything waiting in the receiver, and passes it on.
+    pub fn forward(&mut self, queue: &Receiver<ocad::Object>, post_box: &Sender<ocad::Object>) {
+        for object in queue.try_iter() {
+            self.insert(&object);
+            post_box.send(object).expect("Unable to forward object to OCAD.");
+        }
+    }
+
+    // Distance to the nearest line or area outline of the given kind, if closer than max_distance.
+    pub fn distance_to(&self, kind: FeatureKind, p: &Sweref, max_distance: f64) -> Option<f64> {
+        let (x0, y0) = cell(&Sweref { east: p.east - max_distance, north: p.north - max_distance });
+        let (x1, y1) = cell(&Sweref { east: p.east + max_distance, north: p.north + max_distance });
+        let mut nearest: Option<f64> = None;
+        for x in x0..=x1 {
+            for y in y0..=y1 {
+                for (line, i) in self.line_cells.get(&(x,y)).into_iter().flatten() {
+                    let (k, points) = &self.lines[*line];
+                    if *k != kind { continue }
+                    let d = distance_to_segment(p, &points[*i], &points[*i+1]);
+                    if d <= max_distance && nearest.map(|n| d < n).unwrap_or(true) {
+                        nearest = Some(d);
+                    }
+                }
+            }
+        }
+        nearest
+    }
+
+    pub fn is_near(&self, kind: FeatureKind, p: &Sweref, max_distance: f64) -> bool {
+        self.distance_to(kind, p, max_distance).is_some()
+    }
+
+    pub fn is_inside(&self, kind: FeatureKind, p: &Sweref) -> bool {
+        self.area_cells.get(&cell(p)).into_iter().flatten().any(|a| {
+            let (k, rings) = &self.areas[*a];
+            *k == kind && rings.iter().filter(|ring| ring_contains(ring, p)).count() % 2 == 1
+        })
+    }
+}
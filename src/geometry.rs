@@ -1,5 +1,5 @@
 use super::Sweref;
-use nalgebra::DMatrix;
+use nalgebra::{Matrix3,Vector3};
 use crate::las::LAS_File_Header;
 
 pub struct PointConverter {
@@ -242,58 +242,106 @@ impl Plane {
     pub fn z_normal(&self) -> f64 { self.normal[2] }
 
     pub fn from_points(points: &Vec<Point3D>) -> Option<Self> {
-        /*  β1x + β2y + β3 = z
-            | x1 y1 1 |
-            | x2 y2 1 |
-        X = | x3 y3 1 |  (M = number of points, N = 3)
-            | x4 y4 1 |
-            | x5 y5 1 |
-            | z1 |
-            | z1 |
-        Y = | z1 | (M = number of points, N = 1)
-            | z1 |
-            | z1 |
-            | F1 |
-        β = | F2 | (M = 3, N = 1)
-            | F3 |
-        Y = Xβ
-        OLS: β_opt = (XT X)^(-1) XT Y
-        3xC * Cx3 => 3x3
-        3x3 * 3xC => 3xC
-        3xC * Cx1 => 3x1
-        */
+        // Total least squares: the normal is the direction in which the points vary the
+        // least, i.e. the eigenvector of the covariance matrix with the smallest eigenvalue.
+        // Unlike fitting z = β1x + β2y + β3, this also works for vertical planes.
         if points.len() < 3 { return None }
 
-        let y = DMatrix::from_iterator(points.len(),1,points.iter().map(|p| p.z));    
-        let x = DMatrix::from_iterator(3,points.len(),points.iter().map(|p| vec![p.x, p.y, 1f64]).flatten()).transpose();
-        
-        let (q,r) = x.qr().unpack();
-        if let Some(i) = r.try_inverse() {
-            let b = (i * q.transpose() * y).normalize();
-            let avg_x = points.iter().map(|p| p.x).sum::<f64>()/(points.len() as f64);
-            let avg_y = points.iter().map(|p| p.y).sum::<f64>()/(points.len() as f64);
-            let average_z = points.iter().map(|p| p.z).sum::<f64>()/(points.len() as f64);
-            let z = b[0]*avg_x + b[1]*avg_y + b[2];
-            Some(Plane { 
-                normal: [b[0],b[1],b[2]], 
-                point: Point3D { x: avg_x, y: avg_y, z, },
-                average_z, })
-        } else {
-            None
+        let n = points.len() as f64;
+        let avg_x = points.iter().map(|p| p.x).sum::<f64>()/n;
+        let avg_y = points.iter().map(|p| p.y).sum::<f64>()/n;
+        let average_z = points.iter().map(|p| p.z).sum::<f64>()/n;
+
+        let mut covariance = Matrix3::<f64>::zeros();
+        for p in points.iter() {
+            let d = Vector3::new(p.x - avg_x, p.y - avg_y, p.z - average_z);
+            covariance += d * d.transpose();
         }
+
+        let eigen = covariance.symmetric_eigen();
+        let (smallest, _) = eigen.eigenvalues.iter()
+            .enumerate()
+            .fold((0, f64::MAX), |m, (i, v)| if *v < m.1 { (i, *v) } else { m });
+        // Collinear points do not define a plane.
+        let mut sorted: Vec<f64> = eigen.eigenvalues.iter().cloned().collect();
+        sorted.sort_by(|a,b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        if sorted[1] <= f64::EPSILON * sorted[2] { return None }
+
+        let v = eigen.eigenvectors.column(smallest);
+        let length = v.norm();
+        if !(length > 0f64) { return None }
+        // Normals point upwards.
+        let sign = if v[2] < 0f64 { -1f64 } else { 1f64 };
+        Some(Plane {
+            normal: [sign*v[0]/length, sign*v[1]/length, sign*v[2]/length],
+            point: Point3D { x: avg_x, y: avg_y, z: average_z, },
+            average_z, })
+    }
+
+    // Signed distance along the normal.
+    pub fn distance_to(&self, p: &Point3D) -> f64 {
+        (*p - self.point).dot(&self.normal_as_point())
+    }
+
+    // Horizontal unit vector along the plane. None for horizontal planes.
+    pub fn strike(&self) -> Option<(f64,f64)> {
+        let h = f64::sqrt(self.normal[0]*self.normal[0] + self.normal[1]*self.normal[1]);
+        if h > 0f64 { Some((-self.normal[1]/h, self.normal[0]/h)) } else { None }
     }
 
     pub fn angle_to_vertical(&self) -> f64 {
         f64::acos(self.normal[2]).to_degrees()
     }
 
-    pub fn intersection_with_z(&self, z: f64) -> (Point3D,Point3D) {
-        let x0 = self.point.x;
-        let y0 = (z - self.normal[2] - self.normal[0]*x0)/self.normal[1];
-        let x = self.normal[0];
-        let y = self.normal[1];
-        (Point3D {x: -y + x0, y: x + y0, z, },
-         Point3D {x: y + x0, y: -x + y0, z, })
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Points on a 4 x 4 grid spanned by two directions from a point.
+    fn points_on_plane(origin: Point3D, u: [f64;3], v: [f64;3]) -> Vec<Point3D> {
+        (0..16).map(|k| {
+            let (a, b) = ((k % 4) as f64, (k / 4) as f64);
+            Point3D { x: origin.x + a*u[0] + b*v[0], y: origin.y + a*u[1] + b*v[1], z: origin.z + a*u[2] + b*v[2] }
+        }).collect()
     }
 
-} 
+    fn assert_close(a: f64, b: f64) {
+        assert!(f64::abs(a - b) < 1e-6, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn horizontal_plane() {
+        let plane = Plane::from_points(&points_on_plane(Point3D { x: 10f64, y: 20f64, z: 5f64 }, [1f64, 0f64, 0f64], [0f64, 1f64, 0f64])).unwrap();
+        assert_close(plane.angle_to_vertical(), 0f64);
+        assert_close(plane.z_normal(), 1f64);
+        assert_close(plane.average_z, 5f64);
+        assert!(plane.strike().is_none());
+    }
+
+    #[test]
+    fn vertical_plane() {
+        let plane = Plane::from_points(&points_on_plane(Point3D { x: 3f64, y: 0f64, z: 0f64 }, [0f64, 1f64, 0f64], [0f64, 0f64, 1f64])).unwrap();
+        assert_close(plane.angle_to_vertical(), 90f64);
+        let (sx, sy) = plane.strike().unwrap();
+        assert_close(f64::abs(sy), 1f64);
+        assert_close(sx, 0f64);
+    }
+
+    #[test]
+    fn plane_at_70_degrees() {
+        let a = 70f64.to_radians();
+        // Down the slope, and along it.
+        let plane = Plane::from_points(&points_on_plane(Point3D { x: 0f64, y: 0f64, z: 100f64 }, [f64::cos(a), 0f64, -f64::sin(a)], [0f64, 1f64, 0f64])).unwrap();
+        assert_close(plane.angle_to_vertical(), 70f64);
+        assert!(plane.z_normal() > 0f64);
+        assert_close(plane.distance_to(&Point3D { x: 1f64, y: 5f64, z: 100f64 - f64::tan(a) }), 0f64);
+    }
+
+    #[test]
+    fn collinear_points_have_no_plane() {
+        let points: Vec<Point3D> = (0..5).map(|k| Point3D { x: k as f64, y: 2f64 * (k as f64), z: 1f64 }).collect();
+        assert!(Plane::from_points(&points).is_none());
+    }
+}
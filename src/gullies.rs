@@ -0,0 +1,79 @@
+use super::relief::LocalRelief;
+use super::feature_index::{FeatureIndex,FeatureKind};
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use colored::*;
+
+// Erosion gullies are narrow troughs in the local relief model, the opposite of walls. The
+// ground must curve up sharply on both sides of the trough. Troughs along watercourses from
+// the water model or the map data, and road ditches, are left out.
+
+const MIN_GULLY_DEPTH: f64 = 0.5f64;
+const MAX_GULLY_DEPTH: f64 = 3f64;
+const MIN_DEPTH_FOR_LARGE: f64 = 1f64;
+const RIM_DISTANCE: f64 = 3f64;
+const SIDE_DISTANCE: f64 = 1f64;
+// Both sides must rise, or it is a step in a slope.
+const MIN_SIDE_RISE: f64 = 0.25f64;
+// Second difference across the trough, in m/m², for an incised gully.
+const MIN_CURVATURE: f64 = 0.2f64;
+
+const MAX_TROUGH_WIDTH: f64 = 4f64;
+// Lengths at 1:15000.
+const MIN_GULLY_LENGTH: f64 = 15f64;
+const SIMPLIFICATION_TOLERANCE: f64 = 1f64;
+
+const EXCLUDED_DISTANCE: f64 = 5f64;
+const EXCLUDED_FEATURES: [FeatureKind;2] = [FeatureKind::Watercourse, FeatureKind::Road];
+
+const EROSION_GULLY: i32 = 107000;
+const SMALL_EROSION_GULLY: i32 = 108000;
+
+// Depth of the trough below the ground on both sides.
+fn trough(relief: &LocalRelief, column: usize, row: usize) -> Option<f64> {
+    let ground = &relief.ground;
+    let p = ground.centre(column, row);
+    if p.z.is_nan() || relief.relief.get(column, row) >= 0f64 { return None }
+    (0..4).filter_map(|k| {
+        let angle = (k as f64) * std::f64::consts::FRAC_PI_4;
+        let (ux, uy) = (f64::cos(angle), f64::sin(angle));
+        let at = |d: f64| ground.value_at(p.x + ux*d, p.y + uy*d);
+        let (r1, r2) = (at(-RIM_DISTANCE) - p.z, at(RIM_DISTANCE) - p.z);
+        let curvature = (at(-SIDE_DISTANCE) + at(SIDE_DISTANCE) - 2f64*p.z) / (SIDE_DISTANCE*SIDE_DISTANCE);
+        let depth = 0.5f64 * (r1 + r2);
+        if !(f64::min(r1, r2) >= MIN_SIDE_RISE && curvature >= MIN_CURVATURE &&
+            depth >= MIN_GULLY_DEPTH && depth <= MAX_GULLY_DEPTH) { return None }
+        Some(depth)
+    })
+    .fold(None, |deepest: Option<f64>, d| Some(deepest.map(|e| f64::max(e, d)).unwrap_or(d)))
+}
+
+pub fn detect_gullies(relief: &LocalRelief, features: &FeatureIndex, settings: &MapSettings,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "GULLIES".yellow();
+    let ground = &relief.ground;
+    let troughs: Vec<Option<f64>> = (0..ground.values.len())
+        .map(|i| {
+            let depth = trough(relief, i % ground.columns, i / ground.columns)?;
+            let p = ground.centre(i % ground.columns, i / ground.columns);
+            let p = Sweref { east: p.x, north: p.y };
+            if EXCLUDED_FEATURES.iter().any(|kind| features.is_near(*kind, &p, EXCLUDED_DISTANCE)) { return None }
+            Some(depth)
+        })
+        .collect();
+
+    let mut counts = [0usize; 2];
+    for gully in relief.linear_features(|i| troughs[i].is_some(), MIN_GULLY_LENGTH, MAX_TROUGH_WIDTH, settings).iter() {
+        let depth = gully.cells.iter().map(|(c, r)| troughs[r*ground.columns + c].unwrap()).sum::<f64>() / (gully.cells.len() as f64);
+        let (kind, symbol) = if depth >= MIN_DEPTH_FOR_LARGE { (0, EROSION_GULLY) } else { (1, SMALL_EROSION_GULLY) };
+        post_box.send(gully.object(symbol, depth, SIMPLIFICATION_TOLERANCE, settings)).expect("Unable to send gully!");
+        counts[kind] = counts[kind] + 1;
+    }
+
+    if verbose {
+        println!("[{}] {} erosion gullies and {} small erosion gullies.", &module, counts[0], counts[1]);
+    }
+}
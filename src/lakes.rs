@@ -1,52 +1,124 @@
 use delaunator::EMPTY;
 use colored::*;
 use super::ocad;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel,Sender,Receiver};
+use super::detection::Detection;
 use super::las::PointDataRecord;
 use crate::geometry::{Point3D,PointConverter};
-use super::dtm::{DigitalTerrainModel,Halfedge,Terrain,Z_NORMAL};
-use super::boundary::{Boundary,extract_vertices,extract_interior_segments};
+use super::dtm::{DigitalTerrainModel,Halfedge,Terrain,TriangleWalk,WaterLevel,Z_NORMAL};
+use std::collections::{HashMap,HashSet};
+use super::boundary::{grow_region,Region,RegionLimits,extract_vertices,extract_interior_segments};
 
 const Z_NORMAL_REQUIREMENT: f64 = 0.9993f64;
 const TRIANGLE_CONTAINS_WATER_POINT: usize = 0x80000000;
 const LAKE_INDEX_MASK: usize = 0x7fffffff;
 
-struct Lake<'a> {
-    halfedges: Vec<Halfedge>,
-    index: usize,
-    dtm: &'a DigitalTerrainModel,
-    indices_for_each_triangle: &'a mut Vec<usize>,
+// Many older tiles have no water points. Water returns few or no laser points, so lakes are
+// instead left as voids in the point cloud, covered by long, flat ground triangles.
+const VOID_CELL_SIZE: f64 = 2f64;
+// Fraction of the average density of returns below which a cell is a void.
+const MAX_DENSITY_IN_VOID: f64 = 0.05f64;
+const MIN_AREA_FOR_VOID_SEED: f64 = 20f64;
+const MIN_AREA_FOR_LONG_TRIANGLE: f64 = 5f64;
+const MAX_Z_RANGE_IN_VOID_LAKE: f64 = 0.3f64;
+const MIN_VOID_LAKE_AREA: f64 = 200f64;
+// Fraction of the lake area that must be void.
+const MIN_VOID_FRACTION: f64 = 0.7f64;
+// Lakes with water points are left to find_lakes. Voids this many cells from a water point
+// are part of such a lake.
+const WATER_POINT_MARGIN: i64 = 5;
+const WATER_CLASS: u8 = 9u8;
 
-    normals: &'a Vec<[f64;3]>,
+fn should_grow(dtm: &DigitalTerrainModel, normals: &Vec<[f64;3]>, indices_for_each_triangle: &Vec<usize>, halfedge: Halfedge) -> bool {
+    let triangle = halfedge / 3;
+    indices_for_each_triangle[triangle] & LAKE_INDEX_MASK == 0 && 
+    dtm.terrain[triangle] == Terrain::Unclassified &&
+        (normals[triangle][Z_NORMAL] >= Z_NORMAL_REQUIREMENT ||
+        (dtm.length_of_halfedge(halfedge) > 5.0 && !dtm.exterior[triangle]) ||
+        indices_for_each_triangle[triangle] & TRIANGLE_CONTAINS_WATER_POINT > 0)
 }
 
-impl<'a> Boundary for Lake<'a> {
-    fn claim(&mut self, triangle: usize) { self.indices_for_each_triangle[triangle] = self.index; }
-    fn push_halfedge(&mut self, h: Halfedge) { self.halfedges.push(h); }
-    fn dtm(&self) -> &DigitalTerrainModel { self.dtm }
-    fn get_halfedges(&self) -> &Vec<Halfedge> { &self.halfedges }
-
-    fn should_recurse(&self, halfedge: Halfedge) -> bool {
-        let triangle = halfedge / 3;
-        self.indices_for_each_triangle[triangle] & LAKE_INDEX_MASK == 0 && 
-        self.dtm.terrain[triangle] == Terrain::Unclassified &&
-            (self.normals[triangle][Z_NORMAL] >= Z_NORMAL_REQUIREMENT ||
-            (self.dtm.length_of_halfedge(halfedge) > 5.0 && !self.dtm.exterior[triangle]) || // TODO: also not exterior
-            self.indices_for_each_triangle[triangle] & TRIANGLE_CONTAINS_WATER_POINT > 0)
+// Lake and shore objects for a region at the given water level.
+fn lake_objects(dtm: &DigitalTerrainModel, lake: &Region, water_level: f64) -> Vec<ocad::Object> {
+    let (post_box, objects): (Sender<ocad::Object>, Receiver<ocad::Object>) = channel();
+    let (main, islands) = lake.outer_edge_and_islands(dtm);
+    if main.len() > 3 {
+        let (fill_box, fill) = channel();
+        ocad::post_objects_without_clipping(
+            extract_vertices(dtm, &main, &islands), 
+            &vec![ocad::GraphSymbol::Fill(301002)],
+            &fill_box);
+        for mut object in fill.try_iter() {
+            object.height = Some(water_level);
+            post_box.send(object).expect("Unable to send lake!");
+        }
+
+        let mut border = Vec::new();
+        border.append(&mut extract_interior_segments(dtm, &main));
+        for i in islands.iter() {
+            border.append(&mut extract_interior_segments(dtm, i));
+        }
+        ocad::post_objects_without_clipping(
+            border, 
+            &vec![ocad::GraphSymbol::Stroke(301001, false)],
+            &post_box);            
     }
+    objects.try_iter().collect()
+}
+
+// Lake and shore objects for a grown lake. The lake is levelled at its water level, which is
+// taken from the shoreline, so that the shore is not moved and contours do not follow a step.
+// Returns whether any objects were made.
+fn lake_detection(dtm: &DigitalTerrainModel, z_resolution: f64, lake: Region) -> (Detection, bool) {
+    let lake_vertices: HashSet<usize> = lake.triangles.iter()
+        .flat_map(|t| vec![dtm.vertices[t*3], dtm.vertices[t*3+1], dtm.vertices[t*3+2]])
+        .collect();
+    let shore: Vec<Halfedge> = lake.loops.iter().flatten().cloned().collect();
+    let mut shore_z: Vec<f64> = shore.iter().map(|h| dtm.points[dtm.vertices[*h]].z).collect();
+    if shore_z.len() == 0 {
+        shore_z = lake_vertices.iter().map(|v| dtm.points[*v].z).collect();
+    }
+    shore_z.sort_by(|a,b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let water_level = f64::round(shore_z[shore_z.len()/2]/z_resolution)*z_resolution;
+
+    let objects = lake_objects(dtm, &lake, water_level);
+    let posted = objects.len() > 0;
+
+    // Ground just outside the shore may not be below the water.
+    let mut elevations: HashMap<usize,f64> = lake_vertices.iter().map(|v| (*v, water_level)).collect();
+    for h in shore.iter() {
+        let outside = dtm.opposite(*h);
+        if outside == EMPTY { continue }
+        let v = dtm.vertices[outside.prev()];
+        if !lake_vertices.contains(&v) && dtm.points[v].z <= water_level {
+            elevations.insert(v, water_level + z_resolution);
+        }
+    }
+
+    let location = {
+        let p = dtm.triangle_incenter(lake.triangles[0]);
+        Point3D { x: p.x, y: p.y, z: water_level }
+    };
+    (Detection {
+        terrain: Terrain::Lake,
+        elevations: elevations.into_iter().collect(),
+        water_level: Some(WaterLevel { location, area: lake.area }),
+        triangles: lake.triangles,
+        objects,
+        rebuild: Some(Box::new(move |dtm, region| lake_objects(dtm, region, water_level))),
+    }, posted)
 }
 
 pub fn find_lakes( records: &Vec<PointDataRecord>, point_converter: &PointConverter,
-            dtm: &mut DigitalTerrainModel, 
-            post_box: &Sender<ocad::Object>,
-            verbose: bool) {
+            dtm: &DigitalTerrainModel, 
+            verbose: bool) -> Vec<Detection> {
 
     let module = "LAKE".blue();
     let normals = dtm.normals();
     let z_resolution = point_converter.z_resolution();
 
     let water_points: Vec<Point3D> = records.iter()
-        .filter(|record| record.classification == 9)
+        .filter(|record| record.classification() == 9)
         .map(|record| point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]))
         .collect();
 
@@ -73,8 +145,8 @@ pub fn find_lakes( records: &Vec<PointDataRecord>, point_converter: &PointConver
 
     let mut lake_index: usize = 1;
     let mut actual_lakes = 0;
+    let mut detections = Vec::new();
 
-    let z_limits = dtm.z_limits();
 
     for i in 0..water_points.len() {
         triangle = triangle_indices_for_water_points[i];
@@ -85,64 +157,142 @@ pub fn find_lakes( records: &Vec<PointDataRecord>, point_converter: &PointConver
             continue; 
         }
 
-        let mut lake = Lake {
-            halfedges: Vec::new(),
-            index: lake_index,
-            dtm: dtm,
-            indices_for_each_triangle: &mut lake_indices_for_triangles,
-            normals: &normals,
-        };
+        let lake = grow_region(dtm, triangle, &RegionLimits::none(),
+            |_, halfedge| should_grow(dtm, &normals, &lake_indices_for_triangles, halfedge));
+        for t in lake.triangles.iter() {
+            lake_indices_for_triangles[*t] = lake_index;
+        }
+        let (detection, posted) = lake_detection(dtm, z_resolution, lake);
+        if posted { actual_lakes = actual_lakes + 1; }
+        detections.push(detection);
 
-        lake.grow_from_seed(triangle);
+        lake_index = lake_index + 1;
+    }
+
+    if verbose {
+        println!("[{}] Found {} lakes.", &module, actual_lakes);
+    }
+    detections
+}
 
-        if lake.halfedges.len() > 3 {
-            let (main, islands) = lake.split_into_outer_edge_and_islands();
-            
-            ocad::post_objects_without_clipping(
-                extract_vertices(dtm, &main, &islands), 
-                &vec![ocad::GraphSymbol::Fill(301002)],
-                &post_box);
+// Number of returns of any class in square cells over the DTM, and the cells near water returns.
+struct ReturnDensity {
+    counts: Vec<u32>,
+    near_water: Vec<bool>,
+    columns: usize,
+    rows: usize,
+    x0: f64,
+    y0: f64,
+    average: f64,
+}
 
-            actual_lakes = actual_lakes + 1;
+impl ReturnDensity {
+    fn new(records: &Vec<PointDataRecord>, point_converter: &PointConverter, dtm: &DigitalTerrainModel) -> ReturnDensity {
+        let bounds = dtm.bounds.outset_by(VOID_CELL_SIZE);
+        let columns = ((bounds.upper.x - bounds.lower.x) / VOID_CELL_SIZE).ceil() as usize + 1;
+        let rows = ((bounds.upper.y - bounds.lower.y) / VOID_CELL_SIZE).ceil() as usize + 1;
+        let mut density = ReturnDensity { counts: vec![0; columns*rows], near_water: vec![false; columns*rows],
+            columns, rows, x0: bounds.lower.x, y0: bounds.lower.y, average: 0f64 };
+        let mut inside = 0usize;
+        let mut water = Vec::new();
+        for record in records.iter() {
+            let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+            if let Some(cell) = density.cell(&p) {
+                density.counts[cell] = density.counts[cell] + 1;
+                inside = inside + 1;
+                if record.classification() == WATER_CLASS { water.push(cell); }
+            }
+        }
+        density.average = (inside as f64) / ((columns*rows) as f64);
 
-            let mut border = Vec::new();
-            border.append(&mut extract_interior_segments(dtm, &main));
-            for i in islands.iter() {
-                border.append(&mut extract_interior_segments(dtm, i));
+        water.sort();
+        water.dedup();
+        for cell in water.into_iter() {
+            let (column, row) = ((cell % columns) as i64, (cell / columns) as i64);
+            for c in i64::max(0, column - WATER_POINT_MARGIN)..=i64::min(columns as i64 - 1, column + WATER_POINT_MARGIN) {
+                for r in i64::max(0, row - WATER_POINT_MARGIN)..=i64::min(rows as i64 - 1, row + WATER_POINT_MARGIN) {
+                    density.near_water[(r as usize)*columns + c as usize] = true;
+                }
             }
-            ocad::post_objects_without_clipping(
-                border, 
-                &vec![ocad::GraphSymbol::Stroke(301001, false)],
-                &post_box);            
         }
+        density
+    }
+
+    fn is_near_water(&self, p: &Point3D) -> bool {
+        self.cell(p).map(|cell| self.near_water[cell]).unwrap_or(false)
+    }
+
+    fn cell(&self, p: &Point3D) -> Option<usize> {
+        let column = ((p.x - self.x0) / VOID_CELL_SIZE).floor();
+        let row = ((p.y - self.y0) / VOID_CELL_SIZE).floor();
+        if column < 0f64 || row < 0f64 || column as usize >= self.columns || row as usize >= self.rows {
+            None
+        } else {
+            Some((row as usize)*self.columns + column as usize)
+        }
+    }
 
-        // Alter the dtm so that the z value of all lake triangles is the median z value of the lake.
-        let triangles_for_this_lake: Vec<usize> = lake_indices_for_triangles.iter()
-            .enumerate()
-            .filter(|i| *(i.1) == lake_index)
-            .map(|i| i.0)
-            .collect();
+    fn is_void(&self, p: &Point3D) -> bool {
+        match self.cell(p) {
+            Some(cell) => (self.counts[cell] as f64) < MAX_DENSITY_IN_VOID * self.average,
+            None => false,
+        }
+    }
+}
 
-        let mut average_z: Vec<f64> = triangles_for_this_lake.iter().map(|i| {
-            let (min,max) = z_limits[*i];
-            (min+max)*0.5
-        }).collect();
+pub fn find_lakes_in_voids( records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+            dtm: &DigitalTerrainModel, 
+            verbose: bool) -> Vec<Detection> {
 
-        average_z.sort_by(|a,b| if a < b { std::cmp::Ordering::Less } else { std::cmp::Ordering::Greater });
-        
-        let median_of_average_z = average_z[if average_z.len() > 2 { average_z.len()/2 } else { 0 }];
-        let m = f64::round(median_of_average_z/z_resolution)*z_resolution;
-        for i in triangles_for_this_lake {
-            dtm.points[dtm.vertices[i*3]].z = m;
-            dtm.points[dtm.vertices[i*3+1]].z = m;
-            dtm.points[dtm.vertices[i*3+2]].z = m;
-            dtm.terrain[i] = Terrain::Lake;
+    let module = "LAKE".blue();
+    let normals = dtm.normals();
+    let z_resolution = point_converter.z_resolution();
+    let density = ReturnDensity::new(records, point_converter, dtm);
+
+    let is_void: Vec<bool> = (0..dtm.num_triangles)
+        .map(|t| density.is_void(&dtm.triangle_incenter(t)))
+        .collect();
+    let is_near_water: Vec<bool> = (0..dtm.num_triangles)
+        .map(|t| density.is_near_water(&dtm.triangle_incenter(t)))
+        .collect();
+
+    let limits = RegionLimits { min_area: MIN_VOID_LAKE_AREA, max_z_range: MAX_Z_RANGE_IN_VOID_LAKE, ..RegionLimits::none() };
+    let mut visited = vec![false; dtm.num_triangles];
+    let mut detections = Vec::new();
+    let mut actual_lakes = 0;
+
+    for seed in 0..dtm.num_triangles {
+        if visited[seed] || !is_void[seed] || dtm.exterior[seed] || is_near_water[seed] ||
+            dtm.areas[seed] < MIN_AREA_FOR_VOID_SEED ||
+            normals[seed][Z_NORMAL] < Z_NORMAL_REQUIREMENT { continue }
+
+        // A void that reaches water points is part of a lake that find_lakes grows.
+        let mut reaches_water = false;
+        let lake = grow_region(dtm, seed, &limits, |_, halfedge| {
+            let t = halfedge / 3;
+            let flat = !visited[t] && !dtm.exterior[t] &&
+                dtm.terrain[t] == Terrain::Unclassified &&
+                normals[t][Z_NORMAL] >= Z_NORMAL_REQUIREMENT &&
+                (is_void[t] || dtm.areas[t] > MIN_AREA_FOR_LONG_TRIANGLE);
+            if flat && is_near_water[t] { reaches_water = true; }
+            flat && !is_near_water[t]
+        });
+        for t in lake.triangles.iter() {
+            visited[*t] = true;
         }
 
-        lake_index = lake_index + 1;
+        let void_area: f64 = lake.triangles.iter().filter(|t| is_void[**t]).map(|t| dtm.areas[*t]).sum();
+        if reaches_water || !limits.accepts(&lake) || void_area < MIN_VOID_FRACTION * lake.area { continue }
+
+        let (detection, posted) = lake_detection(dtm, z_resolution, lake);
+        if posted {
+            actual_lakes = actual_lakes + 1;
+            detections.push(detection);
+        }
     }
 
     if verbose {
-        println!("[{}] Found {} lakes.", &module, actual_lakes);
+        println!("[{}] Found {} lakes in voids of the point cloud.", &module, actual_lakes);
     }
-}
\ No newline at end of file
+    detections
+}
@@ -60,14 +60,16 @@ impl LAS_File_Header {
     }
 }
 
+const CLASS_MASK: u8 = 31u8;
+
 #[repr(C, packed)]
 pub struct PointDataRecord {
     pub x: i32,
     pub y: i32,
     pub z: i32,
-    intensity: u16,
+    pub intensity: u16,
     ret: u8,
-    pub classification: u8,
+    classification: u8,
     scan_angle: i8,
     user_data: u8,
     point_source_id: u16,
@@ -85,6 +87,12 @@ struct Lasvlr {
 
 
 impl PointDataRecord {
+    // The upper three bits of the classification byte are the synthetic, key-point and
+    // withheld flags, and the lower five the class.
+    pub fn classification(&self) -> u8 {
+        self.classification & CLASS_MASK
+    }
+
     pub fn load_from(path: &Path, compressed: bool) -> std::io::Result<Vec<PointDataRecord>> {
         let mut file = File::open(path).unwrap();
         let header: LAS_File_Header = read_instance(&mut file).expect("Unable to read LAS file header.");
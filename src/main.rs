@@ -6,6 +6,8 @@ use std::f64::consts::PI;
 use colored::*;
 use std::thread;
 use std::sync::mpsc::{channel,Receiver,Sender};
+use std::sync::Arc;
+use rayon::prelude::*;
 
 mod las;
 mod rek;
@@ -22,13 +24,32 @@ mod dtm;
 mod boundary;
 mod meridians;
 mod cliffs;
+mod boulders;
+mod buildings;
+mod powerlines;
+mod roads;
+mod raster;
+mod relief;
+mod walls;
+mod gullies;
+mod pits;
+mod detection;
+mod water_model;
+mod marshes;
+mod vegetation;
 mod contours;
+mod contour_tree;
+mod contour_validation;
 mod ml_input_data;
 mod hexgrid;
+mod map_settings;
+mod feature_index;
 
 use sweref::Sweref;
 use wgs84::Wgs84;
 use geometry::{Point3D,PointConverter};
+use map_settings::MapSettings;
+use feature_index::FeatureIndex;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -46,6 +67,9 @@ fn main() {
     opts.optflag("q", "quiet", "hide additional information while running");
     opts.optopt("s", "", "shapefiles", "path to a folder containing Lantmäteriet shapefiles.");
     opts.optflag("m", "ml-input-data", "create a .ml-input-data file instead of an OCAD file");
+    opts.optflag("r", "relief-rasters", "write the local relief model and sky-view factor as ESRI ASCII grids");
+    opts.optopt("", "scale", "map scale, 15000 for 1:15000 (default and the only scale the ISOM template supports).", "SCALE");
+    opts.optopt("e", "equidistance", "contour interval in meters, 5 m by default.", "METERS");
     opts.optflag("h", "help", "show this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -63,6 +87,15 @@ fn main() {
 
     let shp_path = matches.opt_str("s");
     let create_ml_data = matches.opt_present("m");
+    let write_relief_rasters = matches.opt_present("r");
+    let settings = match MapSettings::from_options(matches.opt_str("scale"), matches.opt_str("e")) {
+        Ok(settings) => settings,
+        Err(message) => {
+            println!("{}", message);
+            print_usage(&program, opts);
+            return;
+        }
+    };
 
     let appname = match verbose { false => "Snabbkarta", true => r#"
    _____             __    __    __              __       
@@ -101,6 +134,7 @@ fn main() {
     let southwest_corner = Wgs84::from(&bounding_box.southwest);
 
     if verbose {
+        println!("[{}] Map scale 1:{:.0}, {} m contour interval.", &module, settings.scale, settings.equidistance);
         println!("[{}] Lowest point over sea level: {:.0} m", &module, height_over_sea_level);
         println!("[{}] Meridian convergence: {:.2}°", &module, meridian_convergence);
         println!("[{}] Magnetic declination: {:.2}°", &module, magnetic_declination);
@@ -120,15 +154,21 @@ fn main() {
         ocad::create(&output_path, 
             &bounding_box,  
             magnetic_declination + meridian_convergence, 
+            &settings,
             &ocad_rx);
     });
 
+    // Pre-existing map objects are indexed on their way to the OCAD thread.
     let tx_preexisting = ocad_tx.clone();
-    let preexisting_map_thread = thread::spawn(move || {
+    let preexisting_map_thread = thread::spawn(move || -> FeatureIndex {
+        let (tx, rx): (Sender<ocad::Object>, Receiver<ocad::Object>) = channel();
         match shp_path {
-            None => { osm::load_osm(&southwest_corner, &northeast_corner, &tx_preexisting, verbose); },
-            Some(p) => { shapefiles::load_shapefiles(&bounding_box, &Path::new(&p), &lantmateriet::LantmaterietShapes {}, &tx_preexisting, verbose); },
+            None => { osm::load_osm(&southwest_corner, &northeast_corner, &tx, verbose); },
+            Some(p) => { shapefiles::load_shapefiles(&bounding_box, &Path::new(&p), &lantmateriet::LantmaterietShapes {}, &tx, verbose); },
         }
+        let mut index = FeatureIndex::new();
+        index.forward(&rx, &tx_preexisting);
+        index
     });
 
     let records: Vec<las::PointDataRecord> = matches.free.iter().map(|x| 
@@ -136,15 +176,15 @@ fn main() {
     ).flatten().collect();
     println!("[{}] {} point data records in {} files.", &module, records.len(), matches.free.len());
 
-    let low_vegetation = records.iter().filter(|r| r.classification == 3u8).count(); 
-    let medium_vegetation = records.iter().filter(|r| r.classification == 4u8).count(); 
-    let high_vegetation = records.iter().filter(|r| r.classification == 5u8).count(); 
+    let low_vegetation = records.iter().filter(|r| r.classification() == 3u8).count(); 
+    let medium_vegetation = records.iter().filter(|r| r.classification() == 4u8).count(); 
+    let high_vegetation = records.iter().filter(|r| r.classification() == 5u8).count(); 
 
-    let ground_points = records.iter().filter(|r| r.classification == 2u8).count(); 
-    let water_points = records.iter().filter(|r| r.classification == 9u8).count(); 
-    let building_points = records.iter().filter(|r| r.classification & 31 == 6u8).count(); 
+    let ground_points = records.iter().filter(|r| r.classification() == 2u8).count(); 
+    let water_points = records.iter().filter(|r| r.classification() == 9u8).count(); 
+    let building_points = records.iter().filter(|r| r.classification() == 6u8).count(); 
 
-    let unclassified = records.iter().filter(|r| r.classification == 1u8).count(); 
+    let unclassified = records.iter().filter(|r| r.classification() == 1u8).count(); 
 
 
     println!("[{}] {} / {} / {} low / medium / high vegetation points.", &module, low_vegetation, medium_vegetation, high_vegetation);
@@ -162,10 +202,84 @@ fn main() {
 
     // println!("[{}] {} hex grid points generated.", &module, ml_data.len());
 
-    // TODO: run cliffs / lakes in parallel. Hard to do when they both need mutable references
-    // to the dtm.
-    cliffs::detect_cliffs(&mut dtm, &ocad_tx, verbose);
-    lakes::find_lakes(&records, &point_converter, &mut dtm, &ocad_tx, verbose);
+    // Detectors run in parallel on the unchanged DTM. Their findings are then merged into it.
+    let detections: Vec<Vec<detection::Detection>> = {
+        let detectors: Vec<Box<dyn Fn() -> Vec<detection::Detection> + Send + Sync>> = vec![
+            Box::new(|| cliffs::detect_cliffs(&dtm, &settings, verbose)),
+            Box::new(|| lakes::find_lakes(&records, &point_converter, &dtm, verbose)),
+            Box::new(|| lakes::find_lakes_in_voids(&records, &point_converter, &dtm, verbose)),
+            Box::new(|| boulders::detect_boulders(&records, &point_converter, &dtm, &settings, verbose)),
+        ];
+        detectors.par_iter().map(|detect| detect()).collect()
+    };
+    let (detector_tx, detector_rx): (Sender<ocad::Object>, Receiver<ocad::Object>) = channel();
+    detection::merge(&mut dtm, detections, &detector_tx, verbose);
+    if verbose {
+        for w in dtm.water_levels.iter() {
+            println!("[{}] Water level {:.1} m in a {:.0} m² lake at {:.0}, {:.0}.", &module, w.location.z, w.area, w.location.x, w.location.y);
+        }
+    }
+
+    // Vegetation and micro relief are measured once lakes are levelled.
+    let (canopy, relief) = rayon::join(
+        || vegetation::CanopyStatistics::new(&records, &point_converter, &dtm),
+        || relief::LocalRelief::new(&dtm));
+    if write_relief_rasters {
+        let lrm_path = Path::new(&f).with_extension("lrm.asc");
+        let svf_path = Path::new(&f).with_extension("svf.asc");
+        relief.relief.write_esri_ascii(&lrm_path);
+        relief.sky_view.write_esri_ascii(&svf_path);
+        if verbose { println!("[{}] Local relief model and sky-view factor written to {:?} and {:?}", &module, lrm_path, svf_path); }
+    }
+
+    // The remaining detectors compare with the map data. Each round runs in parallel, and sees
+    // what the rounds before it found.
+    let mut feature_index = preexisting_map_thread.join().expect("Unable to finish pre-existing map thread.");
+    feature_index.forward(&detector_rx, &ocad_tx);
+
+    let mut marshes = Vec::new();
+    {
+        let (dtm, records, point_converter, canopy, features, settings) = (&dtm, &records, &point_converter, &canopy, &feature_index, &settings);
+        let marshes = &mut marshes;
+        rayon::scope(|s| {
+            // Marshes are grown once lakes are in the DTM, and kept out of fields and built-up areas.
+            s.spawn(move |_| *marshes = marshes::detect_marshes(dtm, canopy, features, settings, verbose));
+            // Buildings from the point cloud fill in what the map data lacks.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| buildings::extract_buildings(records, point_converter, dtm, features, settings, &tx, verbose));
+            // Power lines missing from the map data.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| powerlines::detect_power_lines(records, point_converter, dtm, canopy, features, &tx, verbose));
+            // Tracks and paths fill gaps in the road network from the map data.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| roads::detect_roads(records, point_converter, dtm, features, settings, &tx, verbose));
+            // Watercourses are checked against the map data, and need the lakes as outlets.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| water_model::find_watercourses(dtm, features, settings, &tx, verbose));
+            // Pits are found once lakes are levelled, since they are outlets for the filling.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| pits::detect_pits(dtm, &tx, verbose));
+        });
+    }
+    detection::merge(&mut dtm, vec![marshes], &detector_tx, verbose);
+    feature_index.forward(&detector_rx, &ocad_tx);
+
+    {
+        let (dtm, canopy, relief, features, settings) = (&dtm, &canopy, &relief, &feature_index, &settings);
+        rayon::scope(|s| {
+            // Vegetation is left to the map data in fields and built-up areas.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| vegetation::map_vegetation(dtm, canopy, features, settings, &tx, verbose));
+            // Walls are kept apart from the roads, including the tracks and paths found above.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| walls::detect_walls(relief, features, settings, &tx, verbose));
+            // Gullies are kept apart from the watercourses.
+            let tx = detector_tx.clone();
+            s.spawn(move |_| gullies::detect_gullies(relief, features, settings, &tx, verbose));
+        });
+    }
+    feature_index.forward(&detector_rx, &ocad_tx);
+    let feature_index = Arc::new(feature_index);
 
     // Divide DTM into 50x50 m sections and save triangles, points. In blocks.
 
@@ -180,14 +294,12 @@ fn main() {
     let contour_thread = {
         let tx_contours = ocad_tx.clone();
         let dtm_clone = dtm.clone();
+        let features = feature_index.clone();
         thread::spawn(move || {
-            contours::create_contours(dtm_clone, min_z, max_z, point_converter.z_resolution(), tx_contours, verbose); })
+            contours::create_contours(dtm_clone, features, min_z, max_z, point_converter.z_resolution(), settings, tx_contours, verbose); })
     };
 
-    meridians::add_meridians(&bounding_box, magnetic_declination+meridian_convergence, &ocad_tx, verbose);
-    // //water_model::rain_on(&mut dtm, &ocad_tx, verbose);
-
-    preexisting_map_thread.join().expect("Unable to finish pre-existing map thread.");
+    meridians::add_meridians(&bounding_box, magnetic_declination+meridian_convergence, &settings, &ocad_tx, verbose);
 
     contour_thread.join().expect("Unable to finish contour thread.");
 
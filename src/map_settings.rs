@@ -0,0 +1,63 @@
+// Map scale and contour interval. Tolerances and minimum sizes in the rest of snabbkarta
+// were chosen for a 1:15000 map with 5 m contours, and are scaled from there.
+
+pub const DEFAULT_SCALE: f64 = 15000f64;
+pub const DEFAULT_EQUIDISTANCE: f64 = 5f64;
+
+// Symbols are drawn at the sizes in the ISOM template, which are those of a 1:15000 map. ISOM
+// draws 1:10000 maps with symbols enlarged to 150%, which the template cannot do, so maps at
+// other scales are refused rather than drawn with symbols of the wrong size.
+const SUPPORTED_SCALES: [f64;1] = [15000f64];
+const MIN_EQUIDISTANCE: f64 = 0.5f64;
+const MAX_EQUIDISTANCE: f64 = 25f64;
+
+// Each contour interval is tried at this many offsets.
+const OFFSETS_PER_EQUIDISTANCE: f64 = 10f64;
+
+#[derive(Clone,Copy,Debug)]
+pub struct MapSettings {
+    pub scale: f64,
+    pub equidistance: f64,
+}
+
+impl MapSettings {
+    // From the command line options, with defaults for those that are not given.
+    pub fn from_options(scale: Option<String>, equidistance: Option<String>) -> Result<MapSettings, String> {
+        let scale = match scale {
+            Some(s) => s.parse::<f64>().map_err(|_| format!("Unable to parse map scale {:?}.", s))?,
+            None => DEFAULT_SCALE,
+        };
+        let equidistance = match equidistance {
+            Some(s) => s.parse::<f64>().map_err(|_| format!("Unable to parse contour interval {:?}.", s))?,
+            None => DEFAULT_EQUIDISTANCE,
+        };
+        if !SUPPORTED_SCALES.contains(&scale) {
+            return Err(format!("Map scale 1:{} is not supported. The ISOM 2017 symbols are drawn for 1:{}.", scale, DEFAULT_SCALE));
+        }
+        // Also rejects NaN and infinity.
+        if !(equidistance >= MIN_EQUIDISTANCE && equidistance <= MAX_EQUIDISTANCE) {
+            return Err(format!("Contour interval must be between {} m and {} m.", MIN_EQUIDISTANCE, MAX_EQUIDISTANCE));
+        }
+        Ok(MapSettings { scale, equidistance })
+    }
+
+    // OCAD coordinates are in units of 0.01 mm on paper.
+    pub fn meters_per_ocad_unit(&self) -> f64 {
+        self.scale * 0.00001f64
+    }
+
+    pub fn contour_step(&self) -> f64 {
+        self.equidistance / OFFSETS_PER_EQUIDISTANCE
+    }
+
+    // A length on the ground that was chosen for 1:15000.
+    pub fn scaled_length(&self, length: f64) -> f64 {
+        length * self.scale / DEFAULT_SCALE
+    }
+
+    // An area on the ground that was chosen for 1:15000.
+    pub fn scaled_area(&self, area: f64) -> f64 {
+        let f = self.scale / DEFAULT_SCALE;
+        area * f * f
+    }
+}
@@ -1,102 +1,177 @@
-use super::dtm::{DigitalTerrainModel,Z_NORMAL,Halfedge,Terrain,TriangleWalk};
-use crate::geometry::Point3D;
+use super::dtm::{DigitalTerrainModel,Z_NORMAL,Terrain};
 use super::ocad;
-use std::sync::mpsc::Sender;
-use super::boundary::Boundary;
-use super::geometry::Plane;
-use std::cmp::Ordering;
+use std::sync::mpsc::{channel,Sender,Receiver};
+use super::boundary::{grow_region,Region,RegionLimits,extract_vertices};
+use super::detection::Detection;
+use super::vegetation::{Canopy,CanopyStatistics};
+use super::map_settings::MapSettings;
+use super::feature_index::{FeatureIndex,MAPPED_AREAS};
+use super::geometry::Point3D;
 use ::geo::{Coordinate,LineString};
 use ::geo::algorithm::simplifyvw::SimplifyVW;
-use ::geo::algorithm::euclidean_length::EuclideanLength;
 use super::Sweref;
 use colored::*;
 use std::f64;
 
-const Z_NORMAL_REQUIREMENT: f64 = 0.9999f64;
+// Marshes are grown from flat seed triangles, staying within a narrow z band. The canopy
+// over the marsh decides its symbol: open, very flat marshes are impassable, and marshes
+// under tall, dense forest are indistinct.
+
+const Z_NORMAL_FOR_SEED: f64 = 0.999f64;
+const Z_NORMAL_FOR_GROW: f64 = 0.995f64;
 const MIN_AREA_FOR_SEED: f64 = 0.6f64;
-const MAX_ANGLE_TO_VERTICAL: f64 = 0.001f64;
-const MAX_Z_DIFF: f64 = 0.4f64;
-const MIN_AREA_FOR_OUTPUT: f64 = 20f64;
+const MAX_Z_RANGE: f64 = 0.3f64;
 
-pub fn detect_marshes_in(dtm: &DigitalTerrainModel, post_box: &Sender<ocad::Object>, center: &Sweref, verbose: bool) {
+// Areas at 1:15000.
+const MIN_MARSH_AREA: f64 = 200f64;
 
-    let module = "MARSH".blue();
+const MAX_COVER_FOR_IMPASSABLE: f64 = 0.05f64;
+const MAX_Z_RANGE_FOR_IMPASSABLE: f64 = 0.15f64;
+const MIN_COVER_FOR_INDISTINCT: f64 = 0.3f64;
+const MIN_TREE_HEIGHT_FOR_INDISTINCT: f64 = 8f64;
 
-    if verbose {
-        println!("[{}] Detecting marshes.", &module);
-    }
+// Long and thin marshes are drawn as narrow marsh lines.
+const MAX_COMPACTNESS_FOR_NARROW: f64 = 0.1f64;
+const MAX_WIDTH_FOR_NARROW: f64 = 3f64;
+const SIMPLIFICATION_TOLERANCE: f64 = 2f64;
 
-    let should_grow = |marsh: &Boundary, halfedge: Halfedge| -> bool {
-        let t = halfedge/3;
-        
-        if marsh.indices_for_each_triangle[t] != 0 || marsh.dtm.terrain[t] != Terrain::Unclassified { 
-            return false; 
-        }
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum MarshType {
+    Impassable,
+    Normal,
+    Narrow,
+    Indistinct,
+}
 
-        let opposing_point = marsh.dtm.points[marsh.dtm.vertices[halfedge.prev()]];
-        // Angle should still be flat
-        // z value of triangle should not deviate too much from average z.
-        let p = marsh.halfedges.iter().map(|h| marsh.dtm.points[marsh.dtm.vertices[*h]] ).collect();
-        //let average_z = p.iter().map(|p| p.z).sum::<f64>()/p.len();
-
-        match Plane::from_points(&p) {
-            Some(plane) => {
-                plane.angle_to_vertical() < MAX_ANGLE_TO_VERTICAL
-//                plane.z_normal() > Z_NORMAL_REQUIREMENT
-                && 
-                f64::abs((opposing_point - plane.point).dot(&plane.normal_as_point())) < MAX_Z_DIFF
-            },
-            _ => p.len() <= 3,
+impl MarshType {
+    fn symbol(&self) -> i32 { match self {
+        Self::Impassable => 307000,
+        Self::Normal => 308000,
+        Self::Narrow => 309000,
+        Self::Indistinct => 310000,
+    }}
+
+    fn classify(region: &Region, canopy: &Canopy, settings: &MapSettings) -> MarshType {
+        // Twice the area over the perimeter is the width of a long strip.
+        let width = 2f64 * region.area / region.perimeter;
+        if region.compactness() < MAX_COMPACTNESS_FOR_NARROW && width < settings.scaled_length(MAX_WIDTH_FOR_NARROW) {
+            MarshType::Narrow
+        } else if canopy.cover < MAX_COVER_FOR_IMPASSABLE && region.z_range() < MAX_Z_RANGE_FOR_IMPASSABLE {
+            MarshType::Impassable
+        } else if canopy.cover > MIN_COVER_FOR_INDISTINCT && canopy.mean_height > MIN_TREE_HEIGHT_FOR_INDISTINCT {
+            MarshType::Indistinct
+        } else {
+            MarshType::Normal
         }
+    }
+}
+
+// The incenters of a narrow marsh, ordered along its main direction.
+fn center_line(dtm: &DigitalTerrainModel, region: &Region) -> Vec<Coordinate<f64>> {
+    let points: Vec<Point3D> = region.triangles.iter().map(|t| dtm.triangle_incenter(*t)).collect();
+    let n = points.len() as f64;
+    let (mx, my) = (points.iter().map(|p| p.x).sum::<f64>() / n, points.iter().map(|p| p.y).sum::<f64>() / n);
+    let (sxx, sxy, syy) = points.iter().fold((0f64, 0f64, 0f64), |s, p| {
+        let (dx, dy) = (p.x - mx, p.y - my);
+        (s.0 + dx*dx, s.1 + dx*dy, s.2 + dy*dy)
+    });
+    // Direction of the largest eigenvector of the covariance matrix.
+    let angle = 0.5f64 * f64::atan2(2f64*sxy, sxx - syy);
+    let (dx, dy) = (f64::cos(angle), f64::sin(angle));
+
+    let mut projected: Vec<(f64, Coordinate<f64>)> = points.iter()
+        .map(|p| ((p.x - mx)*dx + (p.y - my)*dy, Coordinate { x: p.x, y: p.y }))
+        .collect();
+    projected.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    projected.into_iter().map(|(_, c)| c).collect()
+}
+
+fn marsh_objects(dtm: &DigitalTerrainModel, marsh: &Region, marsh_type: MarshType, settings: &MapSettings) -> Vec<ocad::Object> {
+    let (post_box, objects): (Sender<ocad::Object>, Receiver<ocad::Object>) = channel();
+    if marsh_type == MarshType::Narrow {
+        let line = LineString::from(center_line(dtm, marsh)).simplifyvw(&settings.scaled_area(SIMPLIFICATION_TOLERANCE));
+        let segments = line.points_iter()
+            .enumerate()
+            .map(|x| {
+                let s: Sweref = Sweref::from(&x.1);
+                if x.0 == 0 { ocad::Segment::Move(s) } else { ocad::Segment::Line(s) }
+            }).collect();
+        post_box.send(ocad::Object {
+            object_type: ocad::ObjectType::Line(false),
+            symbol_number: marsh_type.symbol(),
+            segments,
+            height: None,
+        }).expect("Unable to send narrow marsh!");
+    } else {
+        let (outer, islands) = marsh.outer_edge_and_islands(dtm);
+        ocad::post_objects_without_clipping(
+            extract_vertices(dtm, &outer, &islands),
+            &vec![ocad::GraphSymbol::Fill(marsh_type.symbol())],
+            &post_box);
+    }
+    objects.try_iter().collect()
+}
+
+pub fn detect_marshes(dtm: &DigitalTerrainModel, canopy: &CanopyStatistics, features: &FeatureIndex,
+    settings: &MapSettings, verbose: bool) -> Vec<Detection> {
+
+    let module = "MARSH".blue();
+    let normals = dtm.normals();
+    let excluded: Vec<bool> = (0..dtm.num_triangles).map(|t| {
+        let p = dtm.triangle_incenter(t);
+        let p = Sweref { east: p.x, north: p.y };
+        MAPPED_AREAS.iter().any(|kind| features.is_inside(*kind, &p))
+    }).collect();
+    let limits = RegionLimits {
+        min_area: settings.scaled_area(MIN_MARSH_AREA),
+        max_z_range: MAX_Z_RANGE,
+        ..RegionLimits::none()
     };
 
-    let mut marsh_index_per_triangle = vec![0 as usize; dtm.num_triangles];
-    let mut marsh_index = 1;
-    let mut num_marshes_output = 0;
+    let mut visited = vec![false; dtm.num_triangles];
+    let mut detections = Vec::new();
+    let mut counts = [0usize; 4];
     let mut total_area_of_marshes = 0f64;
 
-    for (triangle, normal) in dtm.normals().into_iter().enumerate().take(500000) {
-        if  marsh_index_per_triangle[triangle] != 0
-            || dtm.terrain[triangle] != Terrain::Unclassified
-            || normal[Z_NORMAL] < Z_NORMAL_REQUIREMENT
-            || dtm.areas[triangle] < MIN_AREA_FOR_SEED
-            || dtm.exterior[triangle]
+    for seed in 0..dtm.num_triangles {
+        if  visited[seed]
+            || dtm.terrain[seed] != Terrain::Unclassified
+            || normals[seed][Z_NORMAL] < Z_NORMAL_FOR_SEED
+            || dtm.areas[seed] < MIN_AREA_FOR_SEED
+            || dtm.exterior[seed]
+            || excluded[seed]
             { continue }
-        
-            // let p = [dtm.points[dtm.vertices[triangle*3+0]], dtm.points[dtm.vertices[triangle*3+1]], dtm.points[dtm.vertices[triangle*3+2]]];
-            // println!("Seed {} {:?} {} {}",triangle, p, dtm.areas[triangle], normal[Z_NORMAL]);
-
-        // Start growing
-        // We need extra stuff for marsh.
-
-        let mut marsh = Boundary {
-            halfedges: Vec::new(),
-            islands: Vec::new(),
-            index: marsh_index,
-            dtm: dtm,
-            indices_for_each_triangle: &mut marsh_index_per_triangle,
-        };
-
-        marsh.grow_from_triangle(triangle, &should_grow);
-        //marsh.split_into_lake_and_islands();
-
-        if marsh.halfedges.len() > 10 {
-            let area = marsh.indices_for_each_triangle.iter().filter(|i| **i == marsh_index).map(|i| dtm.areas[*i]).sum::<f64>();
-            if area > MIN_AREA_FOR_OUTPUT {
-                ocad::post_objects_without_clipping(
-                    marsh.extract_vertices(), 
-                    &vec![ocad::GraphSymbol::Fill(406000)],
-                    &post_box);
-                total_area_of_marshes = total_area_of_marshes + area;
-                num_marshes_output = num_marshes_output + 1;
-                println!("{} {} {}", triangle, marsh.halfedges.len(), area);
-            }
+
+        let marsh = grow_region(dtm, seed, &limits, |_, halfedge| {
+            let t = halfedge / 3;
+            !visited[t] && !dtm.exterior[t] && !excluded[t] &&
+            dtm.terrain[t] == Terrain::Unclassified &&
+            normals[t][Z_NORMAL] >= Z_NORMAL_FOR_GROW
+        });
+        for t in marsh.triangles.iter() {
+            visited[*t] = true;
         }
-       
-        marsh_index = marsh_index + 1;
+        if !limits.accepts(&marsh) { continue }
+
+        let marsh_type = MarshType::classify(&marsh, &canopy.summary(&marsh.triangles), settings);
+        let settings = *settings;
+        counts[marsh_type as usize] = counts[marsh_type as usize] + 1;
+        total_area_of_marshes = total_area_of_marshes + marsh.area;
+        detections.push(Detection {
+            terrain: Terrain::Marsh,
+            objects: marsh_objects(dtm, &marsh, marsh_type, &settings),
+            triangles: marsh.triangles,
+            elevations: Vec::new(),
+            water_level: None,
+            rebuild: Some(Box::new(move |dtm, region| marsh_objects(dtm, region, marsh_type, &settings))),
+        });
     }
+
     if verbose {
-        println!("[{}] {} marshes detected, total area {:.0} m².", &module, num_marshes_output, total_area_of_marshes);
+        println!("[{}] {} marshes detected, total area {:.0} m².", &module, detections.len(), total_area_of_marshes);
+        println!("[{}] {} impassable, {} normal, {} narrow and {} indistinct.", &module,
+            counts[MarshType::Impassable as usize], counts[MarshType::Normal as usize],
+            counts[MarshType::Narrow as usize], counts[MarshType::Indistinct as usize]);
     }
-
-}
\ No newline at end of file
+    detections
+}
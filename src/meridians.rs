@@ -3,16 +3,20 @@ use super::ocad::{self, GraphSymbol};
 use super::geometry;
 use super::Sweref;
 use colored::*;
+use super::map_settings::MapSettings;
 
-const MERIDIAN_SPACING: f64 = 300.0;
+// Magnetic north lines are 20 mm apart on the map.
+const MERIDIAN_SPACING_ON_MAP: f64 = 0.02;
 
 pub fn add_meridians(bounding_box: &geometry::Rectangle,
     rotation_angle: f64,
+    settings: &MapSettings,
     file: &Sender<ocad::Object>, 
     verbose: bool) {
 
+    let spacing = MERIDIAN_SPACING_ON_MAP * settings.scale;
     if verbose {
-        println!("[{}] Adding meridians at {} m spacing.", "MISC".magenta(), MERIDIAN_SPACING);
+        println!("[{}] Adding meridians at {} m spacing.", "MISC".magenta(), spacing);
     }
 
     let middle = bounding_box.middle();
@@ -34,7 +38,7 @@ pub fn add_meridians(bounding_box: &geometry::Rectangle,
             rotate(&Sweref { east: x, north: rotated_bounding_box.southwest.north, }),
             rotate(&Sweref { east: x, north: rotated_bounding_box.northeast.north, }),
         ]);
-        x = x + MERIDIAN_SPACING;
+        x = x + spacing;
     }
     ocad::post_objects_without_clipping(meridians, &vec![GraphSymbol::Stroke(601000,false)], file);
 }
\ No newline at end of file
@@ -89,7 +89,7 @@ fn construct_partial_ml_input_data( records: &Vec<PointDataRecord>,
     let condensed_records: Vec<CondensedRecord> = records_within_bounds.iter()
         .map(|record| CondensedRecord { 
             point: point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]), 
-            classification: record.classification 
+            classification: record.classification() 
         })
         .collect();
 
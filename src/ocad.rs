@@ -7,11 +7,36 @@ use super::ffi_helpers::*;
 use std::convert::TryInto;
 use std::mem;
 use super::geometry;
+use super::map_settings::MapSettings;
 
 static SOFT_ISOM_2017: &'static [u8] = include_bytes!("../20170608_symboluppsattning_isom_2017_ocad_12.ocd");
 
 use super::Sweref as Point;
 
+// Element heights are stored in 1/256 mm.
+const HEIGHT_UNITS_PER_METER: f64 = 256000f64;
+
+// Symbols that the template lacks are copies of similar ones, with numbers of their own.
+// Problems the detectors leave for the mapper are marked with copies of the registration mark,
+// which is otherwise not used in the generated map, so that each kind can be shown and hidden
+// separately. Minor channels from the water model are told apart from ditches, which the map
+// data draws with the minor water channel.
+pub const CONTOUR_PROBLEM: i32 = 602001;
+pub const ROAD_PROBLEM: i32 = 602002;
+pub const MINOR_CHANNEL: i32 = 306001;
+const REGISTRATION_MARK: i32 = 602000;
+const MINOR_WATER_CHANNEL: i32 = 306000;
+const DERIVED_SYMBOLS: [(i32,i32,&'static str);3] = [
+    (REGISTRATION_MARK, CONTOUR_PROBLEM, "Contour problem"),
+    (REGISTRATION_MARK, ROAD_PROBLEM, "Misaligned road"),
+    (MINOR_WATER_CHANNEL, MINOR_CHANNEL, "Minor channel"),
+];
+
+// Offsets in an OCAD 12 symbol. The description is 64 UTF-16 characters.
+const SYMBOL_NUMBER_OFFSET: usize = 4;
+const DESCRIPTION_OFFSET: usize = 56;
+const DESCRIPTION_LENGTH: usize = 64;
+
 enum PointType {
     Normal,
     FirstBezier,
@@ -29,6 +54,8 @@ pub enum Segment {
 
 #[derive(Debug,PartialEq)]
 pub enum ObjectType {
+    // Angle in degrees counterclockwise from grid east. The map rotation is taken off when
+    // the object is written, as it is for the coordinates.
     Point(f64),
     Area,
     Line(bool),
@@ -54,6 +81,8 @@ pub struct Object {
     pub object_type: ObjectType,
     pub symbol_number: i32,
     pub segments: Vec<Segment>,
+    // Measured height of the feature in meters, e.g. of a cliff.
+    pub height: Option<f64>,
 }
 
 #[derive(Debug)]
@@ -69,6 +98,7 @@ impl Object {
             object_type: ObjectType::Terminate,
             symbol_number: 0i32,
             segments: Vec::new(),
+            height: None,
         }
     }
 
@@ -76,12 +106,13 @@ impl Object {
         match gsymbol {
             GraphSymbol::Stroke(symbol_number, cornerize) => Object { 
                 object_type: ObjectType::Line(*cornerize), 
-                symbol_number: *symbol_number, segments: vec![],
+                symbol_number: *symbol_number, segments: vec![], height: None,
             },
             GraphSymbol::Fill(symbol_number) => Object {
                 object_type: ObjectType::Area,
                 symbol_number: *symbol_number,
                 segments: vec![],
+                height: None,
             },
         }
     }
@@ -91,6 +122,7 @@ impl Object {
             object_type: ObjectType::Point(angle),
             symbol_number,
             segments: vec![Segment::Move(*location)],
+            height: None,
         }
     }
 
@@ -98,7 +130,7 @@ impl Object {
         self.segments.push(s)
     }
 
-    fn polys(&self, angle: f64, middle: &Point) -> Vec<TDPoly> {
+    fn polys(&self, angle: f64, middle: &Point, meters_per_unit: f64) -> Vec<TDPoly> {
 
         fn convert_to_upper_24_bits(p: f64) -> i32 {
             if p < 0f64 {
@@ -114,9 +146,9 @@ impl Object {
         let from_point = |p: &Point, t: &PointType| -> TDPoly {
             // 1 bit = 0.01 mm.
             // Map scale  1:15000 =>
-            // 1 bit 0.15 m.
-            let vx = (p.east - middle.east) / 0.15f64;
-            let vy = (p.north - middle.north) / 0.15f64;
+            // 1 bit 0.15 m, and so on.
+            let vx = (p.east - middle.east) / meters_per_unit;
+            let vy = (p.north - middle.north) / meters_per_unit;
             let x = convert_to_upper_24_bits( vx * c + vy * s);
             let y = convert_to_upper_24_bits(-vx * s + vy * c);
             match t {
@@ -248,6 +280,21 @@ pub fn post_objects(vertex_lists: Vec<Vec<Point>>, symbols: &Vec<GraphSymbol>, p
     }
 }
 
+fn symbol_number(symbol: &Vec<u8>) -> i32 {
+    i32::from_le_bytes(symbol[SYMBOL_NUMBER_OFFSET..SYMBOL_NUMBER_OFFSET+4].try_into().expect("Unable to read symbol number."))
+}
+
+fn renumbered_symbol(symbol: &Vec<u8>, number: i32, description: &str) -> Vec<u8> {
+    let mut copy = symbol.clone();
+    copy[SYMBOL_NUMBER_OFFSET..SYMBOL_NUMBER_OFFSET+4].copy_from_slice(&number.to_le_bytes());
+    let mut characters: Vec<u16> = description.encode_utf16().take(DESCRIPTION_LENGTH - 1).collect();
+    characters.resize(DESCRIPTION_LENGTH, 0u16);
+    for (i, c) in characters.iter().enumerate() {
+        copy[DESCRIPTION_OFFSET+2*i..DESCRIPTION_OFFSET+2*i+2].copy_from_slice(&c.to_le_bytes());
+    }
+    copy
+}
+
 fn load_from_isom() -> (Vec<Vec<u8>>, Vec<Strings>) {
     let mut data = Cursor::new(SOFT_ISOM_2017);
 
@@ -274,6 +321,19 @@ fn load_from_isom() -> (Vec<Vec<u8>>, Vec<Strings>) {
         }
     }
 
+    for (original, number, description) in DERIVED_SYMBOLS.iter() {
+        let position = symbols.iter()
+            .position(|symbol| symbol_number(symbol) == *original)
+            .expect("Unable to find symbol to copy in ISOM file.");
+        let copy = renumbered_symbol(&symbols[position], *number, description);
+        // After the original and any earlier copies of it.
+        let after = symbols[position..].iter()
+            .position(|symbol| symbol_number(symbol) > *number || symbol_number(symbol) / 1000 != original / 1000)
+            .map(|i| position + i)
+            .unwrap_or(symbols.len());
+        symbols.insert(after, copy);
+    }
+
     let mut strings: Vec<Strings> = Vec::new();
 
     let mut next_string_index: u64 = header.stringindex.into();
@@ -298,11 +358,11 @@ fn load_from_isom() -> (Vec<Vec<u8>>, Vec<Strings>) {
     (symbols, strings.into_iter().filter(|x| match x.record_type { 9 | 10 => true, _ => false }).collect())
 }
 
-pub fn create(path: &PathBuf, bounding_box: &geometry::Rectangle, angle: f64, queue: &Receiver<Object>) {
+pub fn create(path: &PathBuf, bounding_box: &geometry::Rectangle, angle: f64, settings: &MapSettings, queue: &Receiver<Object>) {
     let (mut soft_symbols, mut soft_strings) = load_from_isom();
 
     let middle = bounding_box.middle();
-    let m_string = format!("\tm15000.0000\tg0.0000\tr1\tx{:.8}\ty{:.8}\ta{:.8}", middle.east, middle.north, angle);
+    let m_string = format!("\tm{:.4}\tg0.0000\tr1\tx{:.8}\ty{:.8}\ta{:.8}", settings.scale, middle.east, middle.north, angle);
 
     soft_strings.push( Strings {
         s: m_string.as_bytes().to_vec(),
@@ -374,18 +434,18 @@ pub fn create(path: &PathBuf, bounding_box: &geometry::Rectangle, angle: f64, qu
         let object = queue.recv().expect("Unable to receive message on OCAD thread.");
         if object.object_type == ObjectType::Terminate { break; }
 
-        let p = object.polys(angle, &middle);
+        let p = object.polys(angle, &middle, settings.meters_per_ocad_unit());
 
         // Create Element, and fill out object index
         let element = Element {
             symbol_number: object.symbol_number,
             object_type: object.object_type.ocad_object_type(),
-            angle: match object.object_type { ObjectType::Point(a) => (a*10f64) as i16, _ => 0i16 },
+            angle: match object.object_type { ObjectType::Point(a) => ((a - angle).rem_euclid(360f64)*10f64) as i16, _ => 0i16 },
             _color: 0u32,
             _line_width: 0u16,
             _diam_flags: 0u16,
             _server_object_id: 0u32,
-            _height: 0i32,
+            height: object.height.map(|h| (h * HEIGHT_UNITS_PER_METER) as i32).unwrap_or(0i32),
             _creation_date: 0f64,
             _multirepresentationid: 0u32,
             _modification_date: 0f64,
@@ -531,7 +591,7 @@ struct Element {
     _line_width: u16,
     _diam_flags: u16,
     _server_object_id: u32,
-    _height: i32,
+    height: i32,
     _creation_date: f64,
     _multirepresentationid: u32,
     _modification_date: f64,
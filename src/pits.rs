@@ -0,0 +1,123 @@
+use super::dtm::{DigitalTerrainModel,Terrain};
+use super::water_model::Flooded;
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use std::collections::BinaryHeap;
+use colored::*;
+
+// Pits and small depressions are found by filling the ground model from the map edge and the
+// lakes, like water would. Points that are raised by the filling lie in a depression, and
+// each connected group of them is one. Depth, area and the steepness of the sides tell pits
+// from natural depressions. Larger depressions show up in the contours instead.
+
+// Filling less than this is noise in the ground points.
+const MIN_FILL_DEPTH: f64 = 0.05f64;
+const MIN_DEPTH: f64 = 0.5f64;
+const MAX_RADIUS: f64 = 8f64;
+// Depth over the radius of a circle with the same area, for a dug pit.
+const MIN_STEEPNESS_FOR_PIT: f64 = 0.5f64;
+// In m².
+const MIN_AREA_FOR_LARGE: f64 = 30f64;
+const MIN_DEPTH_FOR_LARGE: f64 = 1f64;
+// Symbols are turned along depressions that are this much longer than wide.
+const MIN_ELONGATION_FOR_ORIENTATION: f64 = 2f64;
+
+const SMALL_DEPRESSION: i32 = 111000;
+const PIT: i32 = 112000;
+const LARGE_PIT: i32 = 115000;
+
+// Filled elevation of each point.
+fn fill(dtm: &DigitalTerrainModel, neighbours: &Vec<Vec<usize>>, triangles: &Vec<Vec<usize>>) -> Vec<f64> {
+    let mut filled: Vec<f64> = dtm.points.iter().map(|p| p.z).collect();
+    let mut done = vec![false; dtm.points.len()];
+    let mut heap = BinaryHeap::new();
+    for v in 0..dtm.points.len() {
+        // Points on the convex hull have one more neighbour than triangles.
+        let is_outlet = triangles[v].iter().any(|t| dtm.exterior[*t] || dtm.terrain[*t] == Terrain::Lake) ||
+            neighbours[v].len() > triangles[v].len();
+        if is_outlet {
+            done[v] = true;
+            heap.push(Flooded(filled[v], v));
+        }
+    }
+    while let Some(Flooded(z, v)) = heap.pop() {
+        for n in neighbours[v].iter() {
+            if done[*n] { continue }
+            done[*n] = true;
+            filled[*n] = f64::max(filled[*n], z);
+            heap.push(Flooded(filled[*n], *n));
+        }
+    }
+    filled
+}
+
+pub fn detect_pits(dtm: &DigitalTerrainModel, post_box: &Sender<ocad::Object>, verbose: bool) {
+    let module = "PITS".magenta();
+    let (neighbours, triangles) = dtm.vertex_neighbours();
+    let filled = fill(dtm, &neighbours, &triangles);
+    let depth: Vec<f64> = filled.iter().zip(dtm.points.iter()).map(|(f, p)| f - p.z).collect();
+
+    let mut visited = vec![false; dtm.points.len()];
+    let mut num_depressions = 0;
+    let mut counts = [0usize; 3];
+    for seed in 0..dtm.points.len() {
+        if visited[seed] || depth[seed] < MIN_FILL_DEPTH { continue }
+        visited[seed] = true;
+        let mut members = vec![seed];
+        let mut k = 0;
+        while k < members.len() {
+            for n in neighbours[members[k]].iter() {
+                if !visited[*n] && depth[*n] >= MIN_FILL_DEPTH {
+                    visited[*n] = true;
+                    members.push(*n);
+                }
+            }
+            k = k + 1;
+        }
+        num_depressions = num_depressions + 1;
+
+        let bottom = *members.iter().max_by(|a,b| depth[**a].partial_cmp(&depth[**b]).unwrap_or(std::cmp::Ordering::Equal)).unwrap();
+        let max_depth = depth[bottom];
+        let centre = dtm.points[bottom];
+        let radius = members.iter().map(|m| centre.distance_2d_to(&dtm.points[*m])).fold(0f64, f64::max);
+        if max_depth < MIN_DEPTH || radius > MAX_RADIUS { continue }
+
+        // Each point has a third of the area of the triangles around it.
+        let area = members.iter().map(|m| triangles[*m].iter().map(|t| dtm.areas[*t]).sum::<f64>() / 3f64).sum::<f64>();
+        let steepness = max_depth / f64::max(f64::sqrt(area / std::f64::consts::PI), 1f64);
+        let (kind, symbol) = if area >= MIN_AREA_FOR_LARGE && max_depth >= MIN_DEPTH_FOR_LARGE {
+            (2, LARGE_PIT)
+        } else if steepness >= MIN_STEEPNESS_FOR_PIT {
+            (1, PIT)
+        } else {
+            (0, SMALL_DEPRESSION)
+        };
+
+        // Main axis of the depression.
+        let n = members.len() as f64;
+        let (mx, my) = (members.iter().map(|m| dtm.points[*m].x).sum::<f64>() / n, members.iter().map(|m| dtm.points[*m].y).sum::<f64>() / n);
+        let (sxx, sxy, syy) = members.iter().fold((0f64, 0f64, 0f64), |s, m| {
+            let (dx, dy) = (dtm.points[*m].x - mx, dtm.points[*m].y - my);
+            (s.0 + dx*dx, s.1 + dx*dy, s.2 + dy*dy)
+        });
+        let spread = f64::sqrt((sxx - syy)*(sxx - syy) + 4f64*sxy*sxy);
+        let (major, minor) = (0.5f64*(sxx + syy + spread), 0.5f64*(sxx + syy - spread));
+        let angle = if minor > 0f64 && major / minor >= MIN_ELONGATION_FOR_ORIENTATION * MIN_ELONGATION_FOR_ORIENTATION {
+            (0.5f64 * f64::atan2(2f64*sxy, sxx - syy)).to_degrees()
+        } else {
+            0f64
+        };
+
+        let location = Sweref { east: centre.x, north: centre.y };
+        let mut object = ocad::Object::point_object(symbol, &location, angle);
+        object.height = Some(max_depth);
+        post_box.send(object).expect("Unable to send pit!");
+        counts[kind] = counts[kind] + 1;
+    }
+
+    if verbose {
+        println!("[{}] {} depressions filled, {} small depressions, {} pits and {} large pits.", &module,
+            num_depressions, counts[0], counts[1], counts[2]);
+    }
+}
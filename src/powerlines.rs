@@ -0,0 +1,323 @@
+use super::las::PointDataRecord;
+use crate::geometry::{Point3D,PointConverter};
+use super::dtm::DigitalTerrainModel;
+use super::vegetation::CanopyStatistics;
+use super::feature_index::{FeatureIndex,FeatureKind};
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use std::collections::HashMap;
+use super::raster::connected_cells;
+use colored::*;
+
+// Wires are sparse returns high above the ground, in corridors that are cleared of trees. They
+// are grouped, and each group is split into straight spans. Pylons are where the wires are
+// highest along a span, or where the point cloud has classified them.
+
+const UNCLASSIFIED: u8 = 1u8;
+const WIRE_CONDUCTOR: u8 = 14u8;
+const TRANSMISSION_TOWER: u8 = 15u8;
+
+const MIN_WIRE_HEIGHT: f64 = 5f64;
+// Wires pass above the trees along the corridor.
+const MIN_CLEARANCE_ABOVE_CANOPY: f64 = 1f64;
+// In a cell with a wire, most returns are from the ground below it.
+const SPARSITY_CELL_SIZE: f64 = 2f64;
+const MAX_ELEVATED_FRACTION: f64 = 0.2f64;
+
+// Candidates this close are part of the same line.
+const GROUPING_CELL_SIZE: f64 = 10f64;
+const MIN_POINTS_IN_LINE: usize = 20;
+const MIN_LINE_LENGTH: f64 = 60f64;
+// Parallel conductors spread out sideways, and sag between the pylons.
+const MAX_LATERAL_RMS: f64 = 4f64;
+const MAX_SAG_RMS: f64 = 4f64;
+const MAX_SPLITS: usize = 4;
+
+// Tall or wide lines are transmission lines.
+const MIN_HEIGHT_FOR_MAJOR: f64 = 15f64;
+const MIN_WIDTH_FOR_MAJOR: f64 = 6f64;
+
+const PROFILE_BIN_LENGTH: f64 = 5f64;
+const MIN_SPAN: f64 = 80f64;
+const MIN_SAG_FOR_PYLON: f64 = 1f64;
+const TOWER_CELL_SIZE: f64 = 5f64;
+const MIN_TOWER_POINTS: usize = 5;
+const MAX_TOWER_DISTANCE: f64 = 10f64;
+
+// Spans that end this close to where the next one starts are parts of the same line.
+const MAX_JOIN_DISTANCE: f64 = 10f64;
+
+// Lines mostly along a power line from the map data are already there.
+const EXISTING_LINE_DISTANCE: f64 = 15f64;
+const MAX_FRACTION_NEAR_EXISTING: f64 = 0.5f64;
+const SAMPLE_SPACING: f64 = 10f64;
+
+const POWER_LINE: i32 = 510000;
+const MAJOR_POWER_LINE: i32 = 511000;
+const PYLON: i32 = 511004;
+
+struct Span {
+    start: Sweref,
+    end: Sweref,
+    // Highest points along the wire.
+    pylons: Vec<Sweref>,
+    height: f64,
+    width: f64,
+}
+
+impl Span {
+    fn length(&self) -> f64 {
+        f64::sqrt((self.end.east - self.start.east)*(self.end.east - self.start.east) + (self.end.north - self.start.north)*(self.end.north - self.start.north))
+    }
+
+    fn angle(&self) -> f64 {
+        f64::atan2(self.end.north - self.start.north, self.end.east - self.start.east).to_degrees()
+    }
+
+    fn is_major(&self) -> bool {
+        self.height > MIN_HEIGHT_FOR_MAJOR || self.width > MIN_WIDTH_FOR_MAJOR
+    }
+
+    fn reversed(self) -> Span {
+        Span { start: self.end, end: self.start, ..self }
+    }
+}
+
+fn distance(a: &Sweref, b: &Sweref) -> f64 {
+    f64::sqrt((b.east - a.east)*(b.east - a.east) + (b.north - a.north)*(b.north - a.north))
+}
+
+// Chains the spans of a group that meet end to end, turned so that each starts where the
+// previous one ends. fit_spans returns the spans of a group in order along it.
+fn chain_spans(spans: Vec<Span>) -> Vec<Vec<Span>> {
+    let mut chains: Vec<Vec<Span>> = Vec::new();
+    for span in spans.into_iter() {
+        if let Some(chain) = chains.last_mut() {
+            // A chain of one span may still be turned around.
+            if chain.len() == 1 && f64::min(distance(&chain[0].start, &span.start), distance(&chain[0].start, &span.end)) <
+                f64::min(distance(&chain[0].end, &span.start), distance(&chain[0].end, &span.end)) {
+                let first = chain.pop().unwrap();
+                chain.push(first.reversed());
+            }
+            let end = chain[chain.len()-1].end;
+            if distance(&end, &span.start) < MAX_JOIN_DISTANCE {
+                chain.push(span);
+                continue;
+            }
+            if distance(&end, &span.end) < MAX_JOIN_DISTANCE {
+                chain.push(span.reversed());
+                continue;
+            }
+        }
+        chains.push(vec![span]);
+    }
+    chains
+}
+
+// Wire points with their height above the ground.
+fn wire_candidates(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, canopy: &CanopyStatistics) -> (Vec<(Point3D,f64)>, Vec<Point3D>) {
+
+    let mut returns: HashMap<(i64,i64),(u32,u32)> = HashMap::new();
+    let mut elevated: Vec<(Point3D,f64,bool)> = Vec::new();
+    let mut towers = Vec::new();
+    let mut hint = 0usize;
+    for record in records.iter() {
+        let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+        if !dtm.bounds.contains_2d(&p) { continue }
+        let t = match dtm.triangle_containing_point(&p, hint) {
+            Some(t) => t,
+            None => continue,
+        };
+        hint = t;
+        let height = p.z - dtm.z_coordinate_in_triangle(&p, t);
+        let cell = ((p.x / SPARSITY_CELL_SIZE).floor() as i64, (p.y / SPARSITY_CELL_SIZE).floor() as i64);
+        let counts = returns.entry(cell).or_insert((0, 0));
+        counts.0 = counts.0 + 1;
+        if height < MIN_WIRE_HEIGHT { continue }
+        counts.1 = counts.1 + 1;
+
+        match record.classification() {
+            WIRE_CONDUCTOR => elevated.push((p, height, true)),
+            TRANSMISSION_TOWER => towers.push(p),
+            UNCLASSIFIED if height > canopy.top_height(t) + MIN_CLEARANCE_ABOVE_CANOPY => elevated.push((p, height, false)),
+            _ => {},
+        }
+    }
+
+    let wires = elevated.into_iter()
+        .filter(|(p, _, classified)| {
+            if *classified { return true }
+            let counts = returns[&((p.x / SPARSITY_CELL_SIZE).floor() as i64, (p.y / SPARSITY_CELL_SIZE).floor() as i64)];
+            (counts.1 as f64) <= MAX_ELEVATED_FRACTION * (counts.0 as f64)
+        })
+        .map(|(p, h, _)| (p, h))
+        .collect();
+    (wires, towers)
+}
+
+// Connected groups of points, on a grid of the given size.
+fn groups<T: Copy>(items: &Vec<T>, position: impl Fn(&T) -> Point3D, cell_size: f64) -> Vec<Vec<T>> {
+    let mut cells: HashMap<(i64,i64),Vec<T>> = HashMap::new();
+    for item in items.iter() {
+        let p = position(item);
+        cells.entry(((p.x / cell_size).floor() as i64, (p.y / cell_size).floor() as i64)).or_insert(Vec::new()).push(*item);
+    }
+    connected_cells(&cells.keys().cloned().collect())
+        .iter()
+        .map(|group| group.iter().flat_map(|c| cells[c].iter().cloned()).collect())
+        .collect()
+}
+
+// Splits the points into straight spans. A group that is not straight is halved along its main
+// direction, since lines turn at pylons.
+fn fit_spans(points: &Vec<(Point3D,f64)>, depth: usize) -> Vec<Span> {
+    if points.len() < MIN_POINTS_IN_LINE { return Vec::new() }
+    let n = points.len() as f64;
+    let (mx, my) = (points.iter().map(|p| p.0.x).sum::<f64>() / n, points.iter().map(|p| p.0.y).sum::<f64>() / n);
+    let (sxx, sxy, syy) = points.iter().fold((0f64, 0f64, 0f64), |s, p| {
+        let (dx, dy) = (p.0.x - mx, p.0.y - my);
+        (s.0 + dx*dx, s.1 + dx*dy, s.2 + dy*dy)
+    });
+    let angle = 0.5f64 * f64::atan2(2f64*sxy, sxx - syy);
+    let (ux, uy) = (f64::cos(angle), f64::sin(angle));
+
+    // Distance along and across the line, and height.
+    let mut projected: Vec<(f64,f64,f64,f64)> = points.iter()
+        .map(|p| ((p.0.x - mx)*ux + (p.0.y - my)*uy, -(p.0.x - mx)*uy + (p.0.y - my)*ux, p.0.z, p.1))
+        .collect();
+    projected.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let (s0, s1) = (projected[0].0, projected[projected.len()-1].0);
+    let lateral_rms = f64::sqrt(projected.iter().map(|p| p.1*p.1).sum::<f64>() / n);
+
+    // Straight line fit of z along the span.
+    let ms = projected.iter().map(|p| p.0).sum::<f64>() / n;
+    let mz = projected.iter().map(|p| p.2).sum::<f64>() / n;
+    let sss = projected.iter().map(|p| (p.0 - ms)*(p.0 - ms)).sum::<f64>();
+    let slope = if sss > 0f64 { projected.iter().map(|p| (p.0 - ms)*(p.2 - mz)).sum::<f64>() / sss } else { 0f64 };
+    let residual = |p: &(f64,f64,f64,f64)| p.2 - (mz + slope*(p.0 - ms));
+    let sag_rms = f64::sqrt(projected.iter().map(|p| residual(p)*residual(p)).sum::<f64>() / n);
+
+    if lateral_rms > MAX_LATERAL_RMS || sag_rms > MAX_SAG_RMS {
+        if depth >= MAX_SPLITS { return Vec::new() }
+        let middle = 0.5f64 * (s0 + s1);
+        let (first, second): (Vec<_>, Vec<_>) = points.iter().cloned().partition(|p| (p.0.x - mx)*ux + (p.0.y - my)*uy < middle);
+        let mut spans = fit_spans(&first, depth + 1);
+        spans.append(&mut fit_spans(&second, depth + 1));
+        return spans;
+    }
+    if s1 - s0 < MIN_LINE_LENGTH { return Vec::new() }
+
+    // Highest residual in each bin of the profile. A bin that is the highest within half a span
+    // in both directions, with the wire sagging on both sides, carries a pylon.
+    let num_bins = ((s1 - s0) / PROFILE_BIN_LENGTH).ceil() as usize + 1;
+    let mut profile: Vec<Option<f64>> = vec![None; num_bins];
+    for p in projected.iter() {
+        let bin = ((p.0 - s0) / PROFILE_BIN_LENGTH) as usize;
+        profile[bin] = Some(profile[bin].map(|r| f64::max(r, residual(p))).unwrap_or(residual(p)));
+    }
+    let window = (0.5f64 * MIN_SPAN / PROFILE_BIN_LENGTH) as usize;
+    let at = |s: f64| Sweref { east: mx + s*ux, north: my + s*uy };
+    let pylons = (0..num_bins).filter(|i| {
+        let r = match profile[*i] { Some(r) => r, None => return false };
+        let before = &profile[i.saturating_sub(window)..*i];
+        let after = &profile[usize::min(num_bins, i+1)..usize::min(num_bins, i+1+window)];
+        let lowest = |side: &[Option<f64>]| side.iter().flatten().cloned().fold(f64::MAX, f64::min);
+        before.iter().chain(after.iter()).flatten().all(|o| *o <= r) &&
+            r - lowest(before) >= MIN_SAG_FOR_PYLON && r - lowest(after) >= MIN_SAG_FOR_PYLON
+    }).map(|i| at(s0 + ((i as f64) + 0.5f64)*PROFILE_BIN_LENGTH)).collect();
+
+    let mut heights: Vec<f64> = projected.iter().map(|p| p.3).collect();
+    heights.sort_by(|a,b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let (c0, c1) = projected.iter().fold((f64::MAX, f64::MIN), |c, p| (f64::min(c.0, p.1), f64::max(c.1, p.1)));
+    vec![Span { start: at(s0), end: at(s1), pylons, height: heights[heights.len() / 2], width: c1 - c0 }]
+}
+
+pub fn detect_power_lines(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, canopy: &CanopyStatistics, features: &FeatureIndex,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "POWER".yellow();
+    let (wires, towers) = wire_candidates(records, point_converter, dtm, canopy);
+    if verbose {
+        println!("[{}] {} wire candidates and {} tower points.", &module, wires.len(), towers.len());
+    }
+
+    let towers: Vec<Sweref> = groups(&towers, |p| *p, TOWER_CELL_SIZE).iter()
+        .filter(|g| g.len() >= MIN_TOWER_POINTS)
+        .map(|g| {
+            let n = g.len() as f64;
+            Sweref { east: g.iter().map(|p| p.x).sum::<f64>() / n, north: g.iter().map(|p| p.y).sum::<f64>() / n }
+        })
+        .collect();
+
+    let mut num_lines = 0;
+    let mut num_existing = 0;
+    let mut num_pylons = 0;
+    for group in groups(&wires, |w| w.0, GROUPING_CELL_SIZE).iter() {
+        for chain in chain_spans(fit_spans(group, 0)).into_iter() {
+            let length: f64 = chain.iter().map(|s| s.length()).sum();
+            let samples: Vec<Sweref> = chain.iter()
+                .flat_map(|span| {
+                    let num_samples = usize::max(1, (span.length() / SAMPLE_SPACING) as usize);
+                    (0..=num_samples).map(move |i| {
+                        let f = (i as f64) / (num_samples as f64);
+                        Sweref { east: span.start.east + f*(span.end.east - span.start.east), north: span.start.north + f*(span.end.north - span.start.north) }
+                    })
+                })
+                .collect();
+            let near_existing = samples.iter().filter(|p| features.is_near(FeatureKind::PowerLine, p, EXISTING_LINE_DISTANCE)).count();
+            if (near_existing as f64) > MAX_FRACTION_NEAR_EXISTING * (samples.len() as f64) {
+                num_existing = num_existing + 1;
+                continue;
+            }
+
+            // The line turns at a pylon halfway between the end of one span and the start of the next.
+            let mut vertices = vec![chain[0].start];
+            let mut turns = Vec::new();
+            for pair in chain.windows(2) {
+                let turn = Sweref { east: 0.5f64*(pair[0].end.east + pair[1].start.east), north: 0.5f64*(pair[0].end.north + pair[1].start.north) };
+                let angle = f64::atan2(pair[1].end.north - pair[0].start.north, pair[1].end.east - pair[0].start.east).to_degrees();
+                vertices.push(turn);
+                turns.push((turn, angle));
+            }
+            vertices.push(chain[chain.len()-1].end);
+
+            let major_length: f64 = chain.iter().filter(|s| s.is_major()).map(|s| s.length()).sum();
+            let is_major = major_length > 0.5f64 * length;
+            let height = chain.iter().map(|s| s.height * s.length()).sum::<f64>() / length;
+            post_box.send(ocad::Object {
+                object_type: ocad::ObjectType::Line(true),
+                symbol_number: if is_major { MAJOR_POWER_LINE } else { POWER_LINE },
+                segments: vertices.iter()
+                    .enumerate()
+                    .map(|(i, v)| if i == 0 { ocad::Segment::Move(*v) } else { ocad::Segment::Line(*v) })
+                    .collect(),
+                height: Some(height),
+            }).expect("Unable to send power line!");
+            num_lines = num_lines + 1;
+            if !is_major { continue }
+
+            // Classified towers are used when the point cloud has them.
+            let near_span = |p: &Sweref| chain.iter()
+                .find(|span| super::feature_index::distance_to_segment(p, &span.start, &span.end) < MAX_TOWER_DISTANCE);
+            let pylons: Vec<(Sweref,f64)> = if towers.iter().any(|p| near_span(p).is_some()) {
+                towers.iter().filter_map(|p| near_span(p).map(|span| (*p, span.angle()))).collect()
+            } else {
+                chain.iter()
+                    .flat_map(|span| span.pylons.iter().map(move |p| (*p, span.angle())))
+                    .chain(turns.into_iter())
+                    .collect()
+            };
+            for (p, angle) in pylons.iter() {
+                post_box.send(ocad::Object::point_object(PYLON, p, *angle)).expect("Unable to send pylon!");
+                num_pylons = num_pylons + 1;
+            }
+        }
+    }
+
+    if verbose {
+        println!("[{}] {} power lines with {} pylons created, {} already in the map data.", &module, num_lines, num_pylons, num_existing);
+    }
+}
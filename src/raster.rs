@@ -0,0 +1,225 @@
+use crate::geometry::{Bounds,Point3D};
+use super::dtm::DigitalTerrainModel;
+use std::collections::{HashMap,HashSet,VecDeque};
+use std::fs::File;
+use std::io::{BufWriter,Write};
+use std::path::Path;
+
+// A regular grid of values over the map, for detectors that work better on cells than on
+// triangles. Cells without a value hold NaN.
+
+const NODATA: f64 = -9999f64;
+const SWEREF99_TM: &'static str = "PROJCS[\"SWEREF99_TM\",GEOGCS[\"GCS_SWEREF99\",DATUM[\"D_SWEREF99\",SPHEROID[\"GRS_1980\",6378137.0,298.257222101]],PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]],PROJECTION[\"Transverse_Mercator\"],PARAMETER[\"False_Easting\",500000.0],PARAMETER[\"False_Northing\",0.0],PARAMETER[\"Central_Meridian\",15.0],PARAMETER[\"Scale_Factor\",0.9996],PARAMETER[\"Latitude_Of_Origin\",0.0],UNIT[\"Meter\",1.0]]";
+
+#[derive(Clone,Debug)]
+pub struct Raster {
+    pub x0: f64,
+    pub y0: f64,
+    pub cell_size: f64,
+    pub columns: usize,
+    pub rows: usize,
+    pub values: Vec<f64>,
+}
+
+impl Raster {
+    pub fn new(bounds: &Bounds, cell_size: f64) -> Raster {
+        let columns = ((bounds.upper.x - bounds.lower.x) / cell_size).ceil() as usize + 1;
+        let rows = ((bounds.upper.y - bounds.lower.y) / cell_size).ceil() as usize + 1;
+        Raster { x0: bounds.lower.x, y0: bounds.lower.y, cell_size, columns, rows, values: vec![f64::NAN; columns*rows], }
+    }
+
+    // Ground elevation at the centre of each cell.
+    pub fn from_dtm(dtm: &DigitalTerrainModel, cell_size: f64) -> Raster {
+        let mut raster = Raster::new(&dtm.bounds, cell_size);
+        let mut hint = 0usize;
+        for row in 0..raster.rows {
+            for column in 0..raster.columns {
+                let p = raster.centre(column, row);
+                if let Some(t) = dtm.triangle_containing_point(&p, hint) {
+                    hint = t;
+                    if !dtm.exterior[t] {
+                        raster.values[row*raster.columns + column] = dtm.z_coordinate_in_triangle(&p, t);
+                    }
+                }
+            }
+        }
+        raster
+    }
+
+    // Same grid, without values.
+    pub fn empty_like(&self) -> Raster {
+        Raster { values: vec![f64::NAN; self.values.len()], ..self.clone() }
+    }
+
+    pub fn cell_of(&self, x: f64, y: f64) -> Option<(usize,usize)> {
+        let column = ((x - self.x0) / self.cell_size).floor();
+        let row = ((y - self.y0) / self.cell_size).floor();
+        if column < 0f64 || row < 0f64 || column >= self.columns as f64 || row >= self.rows as f64 { return None }
+        Some((column as usize, row as usize))
+    }
+
+    pub fn centre(&self, column: usize, row: usize) -> Point3D {
+        Point3D {
+            x: self.x0 + ((column as f64) + 0.5f64) * self.cell_size,
+            y: self.y0 + ((row as f64) + 0.5f64) * self.cell_size,
+            z: self.get(column, row),
+        }
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> f64 {
+        self.values[row*self.columns + column]
+    }
+
+    pub fn set(&mut self, column: usize, row: usize, value: f64) {
+        self.values[row*self.columns + column] = value;
+    }
+
+    pub fn value_at(&self, x: f64, y: f64) -> f64 {
+        match self.cell_of(x, y) {
+            Some((column, row)) => self.get(column, row),
+            None => f64::NAN,
+        }
+    }
+
+    // Mean over a square window of radius cells in each direction, skipping cells without a
+    // value. Summed area tables keep this fast for large windows.
+    pub fn mean_filter(&self, radius: usize) -> Raster {
+        let (w, h) = (self.columns + 1, self.rows + 1);
+        let mut sums = vec![0f64; w*h];
+        let mut counts = vec![0f64; w*h];
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let v = self.get(column, row);
+                let (s, c) = if v.is_nan() { (0f64, 0f64) } else { (v, 1f64) };
+                let i = (row+1)*w + column+1;
+                sums[i] = s + sums[i-1] + sums[i-w] - sums[i-w-1];
+                counts[i] = c + counts[i-1] + counts[i-w] - counts[i-w-1];
+            }
+        }
+
+        let mut filtered = self.empty_like();
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                if self.get(column, row).is_nan() { continue }
+                let (c0, c1) = (column.saturating_sub(radius), usize::min(self.columns, column + radius + 1));
+                let (r0, r1) = (row.saturating_sub(radius), usize::min(self.rows, row + radius + 1));
+                let window = |table: &Vec<f64>| table[r1*w + c1] - table[r0*w + c1] - table[r1*w + c0] + table[r0*w + c0];
+                let n = window(&counts);
+                if n > 0f64 {
+                    filtered.set(column, row, window(&sums) / n);
+                }
+            }
+        }
+        filtered
+    }
+
+    pub fn neighbours(&self, cell: (usize,usize)) -> impl Iterator<Item=(usize,usize)> + '_ {
+        let (column, row) = (cell.0 as i64, cell.1 as i64);
+        (-1..=1).flat_map(move |dx| (-1..=1).map(move |dy| (column + dx, row + dy)))
+            .filter(move |(c, r)| (*c, *r) != (column, row) && *c >= 0 && *r >= 0 && (*c as usize) < self.columns && (*r as usize) < self.rows)
+            .map(|(c, r)| (c as usize, r as usize))
+    }
+
+    // Breadth first search within the cells. Returns the route from the farthest cell back to
+    // the start.
+    fn farthest(&self, cells: &HashSet<(usize,usize)>, start: (usize,usize)) -> Vec<(usize,usize)> {
+        let mut parent = HashMap::new();
+        let mut queue = VecDeque::new();
+        parent.insert(start, start);
+        queue.push_back(start);
+        let mut last = start;
+        while let Some(cell) = queue.pop_front() {
+            last = cell;
+            for n in self.neighbours(cell) {
+                if cells.contains(&n) && !parent.contains_key(&n) {
+                    parent.insert(n, cell);
+                    queue.push_back(n);
+                }
+            }
+        }
+        let mut route = vec![last];
+        while route[route.len()-1] != start {
+            route.push(parent[&route[route.len()-1]]);
+        }
+        route
+    }
+
+    // Route between the two ends of a connected group of cells. One end is the cell farthest
+    // from anywhere in the group, and the other is the cell farthest from that.
+    pub fn longest_route(&self, cells: &HashSet<(usize,usize)>, start: (usize,usize)) -> Vec<(usize,usize)> {
+        let end = self.farthest(cells, start)[0];
+        self.farthest(cells, end)
+    }
+
+    // Connected groups of the cells where the predicate holds.
+    pub fn connected_groups<F>(&self, is_member: F) -> Vec<HashSet<(usize,usize)>> where F: Fn(usize) -> bool {
+        let mut visited = vec![false; self.values.len()];
+        let mut groups = Vec::new();
+        for seed in 0..self.values.len() {
+            if visited[seed] || !is_member(seed) { continue }
+            let mut group = HashSet::new();
+            let mut queue = VecDeque::new();
+            visited[seed] = true;
+            queue.push_back((seed % self.columns, seed / self.columns));
+            while let Some(cell) = queue.pop_front() {
+                group.insert(cell);
+                for (c, r) in self.neighbours(cell) {
+                    let i = r*self.columns + c;
+                    if !visited[i] && is_member(i) {
+                        visited[i] = true;
+                        queue.push_back((c, r));
+                    }
+                }
+            }
+            groups.push(group);
+        }
+        groups
+    }
+
+    // ESRI ASCII grid, with a .prj file next to it so that GIS programs can place it.
+    pub fn write_esri_ascii(&self, path: &Path) {
+        let file = File::create(path).expect("Unable to create raster file.");
+        let mut writer = BufWriter::new(file);
+        write!(writer, "ncols {}\nnrows {}\nxllcorner {:.3}\nyllcorner {:.3}\ncellsize {:.3}\nNODATA_value {}\n",
+            self.columns, self.rows, self.x0, self.y0, self.cell_size, NODATA).expect("Unable to write raster header.");
+        // Rows are written from the north.
+        for row in (0..self.rows).rev() {
+            let line: Vec<String> = (0..self.columns)
+                .map(|column| {
+                    let v = self.get(column, row);
+                    if v.is_nan() { format!("{}", NODATA) } else { format!("{:.3}", v) }
+                })
+                .collect();
+            writeln!(writer, "{}", line.join(" ")).expect("Unable to write raster row.");
+        }
+        std::fs::write(path.with_extension("prj"), SWEREF99_TM).expect("Unable to write raster projection file.");
+    }
+
+    // Cell by cell difference, e.g. of the ground and a smoothed ground.
+    pub fn minus(&self, other: &Raster) -> Raster {
+        Raster { values: self.values.iter().zip(other.values.iter()).map(|(a, b)| a - b).collect(), ..self.clone() }
+    }
+}
+
+// Connected groups of cells on an unbounded grid, for sparse points where most cells of a
+// Raster would be empty. Diagonal neighbours are connected.
+pub fn connected_cells(cells: &HashSet<(i64,i64)>) -> Vec<Vec<(i64,i64)>> {
+    let mut remaining = cells.clone();
+    let mut groups = Vec::new();
+    while let Some(start) = remaining.iter().next().cloned() {
+        remaining.remove(&start);
+        let mut group = vec![start];
+        let mut k = 0;
+        while k < group.len() {
+            let (x, y) = group[k];
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    if remaining.remove(&(x+dx, y+dy)) { group.push((x+dx, y+dy)); }
+                }
+            }
+            k = k + 1;
+        }
+        groups.push(group);
+    }
+    groups
+}
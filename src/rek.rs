@@ -194,6 +194,7 @@ pub fn save_dtm_to_rek(dtm: &DigitalTerrainModel,
                                 Terrain::Unclassified => 0,
                                 Terrain::Lake => 1,
                                 Terrain::Cliff => 2,
+                                Terrain::Marsh => 3,
                             },
                             uid: t as u32,
                         }).collect(),
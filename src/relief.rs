@@ -0,0 +1,106 @@
+use super::dtm::DigitalTerrainModel;
+use super::raster::Raster;
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use std::collections::HashSet;
+use rayon::prelude::*;
+use ::geo::{Coordinate,LineString};
+use ::geo::algorithm::simplifyvw::SimplifyVW;
+use ::geo::algorithm::euclidean_length::EuclideanLength;
+
+// Local relief model: the ground minus the ground smoothed over a wide window. Large scale
+// terrain is removed, and micro relief like walls, pits and gullies is left.
+//
+// Sky-view factor: the fraction of the sky that is visible from the ground, from the highest
+// horizon in eight directions. It is low in pits and gullies, and high on crests.
+
+pub const CELL_SIZE: f64 = 1f64;
+// In cells, on each side.
+const SMOOTHING_RADIUS: usize = 10;
+const HORIZON_RADIUS: usize = 10;
+const HORIZON_DIRECTIONS: [(i64,i64);8] = [(1,0), (1,1), (0,1), (-1,1), (-1,0), (-1,-1), (0,-1), (1,-1)];
+
+pub struct LocalRelief {
+    pub ground: Raster,
+    pub relief: Raster,
+    pub sky_view: Raster,
+}
+
+// A long and narrow group of cells, like a wall or a gully, and the longest route through it.
+pub struct LinearFeature {
+    pub cells: HashSet<(usize,usize)>,
+    pub route: LineString<f64>,
+}
+
+impl LinearFeature {
+    // Line object along the route, simplified with a tolerance at 1:15000.
+    pub fn object(&self, symbol_number: i32, height: f64, tolerance: f64, settings: &MapSettings) -> ocad::Object {
+        let segments = self.route.simplifyvw(&settings.scaled_area(tolerance))
+            .points_iter()
+            .enumerate()
+            .map(|x| {
+                let s: Sweref = Sweref::from(&x.1);
+                if x.0 == 0 { ocad::Segment::Move(s) } else { ocad::Segment::Line(s) }
+            }).collect();
+        ocad::Object {
+            object_type: ocad::ObjectType::Line(false),
+            symbol_number,
+            segments,
+            height: Some(height),
+        }
+    }
+}
+
+fn sky_view_factor(ground: &Raster) -> Raster {
+    let values = (0..ground.values.len()).into_par_iter().map(|i| {
+        let (column, row) = ((i % ground.columns) as i64, (i / ground.columns) as i64);
+        let z = ground.values[i];
+        if z.is_nan() { return f64::NAN }
+        let sines: f64 = HORIZON_DIRECTIONS.iter().map(|(dx, dy)| {
+            let step = ground.cell_size * f64::sqrt((dx*dx + dy*dy) as f64);
+            (1..=HORIZON_RADIUS as i64)
+                .map(|k| (column + k*dx, row + k*dy, (k as f64) * step))
+                .take_while(|(c, r, _)| *c >= 0 && *r >= 0 && (*c as usize) < ground.columns && (*r as usize) < ground.rows)
+                .map(|(c, r, d)| {
+                    let dz = ground.get(c as usize, r as usize) - z;
+                    if dz.is_nan() { 0f64 } else { dz / f64::sqrt(dz*dz + d*d) }
+                })
+                .fold(0f64, f64::max)
+        }).sum();
+        1f64 - sines / (HORIZON_DIRECTIONS.len() as f64)
+    }).collect();
+    Raster { values, ..ground.clone() }
+}
+
+impl LocalRelief {
+    pub fn new(dtm: &DigitalTerrainModel) -> LocalRelief {
+        let ground = Raster::from_dtm(dtm, CELL_SIZE);
+        let relief = ground.minus(&ground.mean_filter(SMOOTHING_RADIUS));
+        let sky_view = sky_view_factor(&ground);
+        LocalRelief { ground, relief, sky_view }
+    }
+
+    // Connected groups of the cells where the predicate holds that are at least the minimum
+    // length at 1:15000, and on average at most the maximum width in m.
+    pub fn linear_features<F>(&self, is_member: F, min_length: f64, max_width: f64, settings: &MapSettings) -> Vec<LinearFeature>
+        where F: Fn(usize) -> bool {
+        let ground = &self.ground;
+        ground.connected_groups(is_member).into_iter()
+            .filter_map(|cells| {
+                let start = *cells.iter().next().unwrap();
+                let route: Vec<Coordinate<f64>> = ground.longest_route(&cells, start).iter()
+                    .map(|(c, r)| {
+                        let p = ground.centre(*c, *r);
+                        Coordinate { x: p.x, y: p.y }
+                    })
+                    .collect();
+                let route = LineString::from(route);
+                let length = route.euclidean_length();
+                if length < settings.scaled_length(min_length) ||
+                    (cells.len() as f64) * CELL_SIZE * CELL_SIZE > max_width * length { return None }
+                Some(LinearFeature { cells, route })
+            })
+            .collect()
+    }
+}
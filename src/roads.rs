@@ -0,0 +1,147 @@
+use super::las::PointDataRecord;
+use crate::geometry::PointConverter;
+use super::dtm::DigitalTerrainModel;
+use super::raster::Raster;
+use super::feature_index::{FeatureIndex,FeatureKind,MAPPED_AREAS};
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use ::geo::{Coordinate,LineString};
+use ::geo::algorithm::simplifyvw::SimplifyVW;
+use colored::*;
+
+// Tracks and paths are narrow strips of smooth ground, where the ground returns are brighter
+// or darker than around them. Candidate cells are grouped into corridors, and the longest
+// route through each long and narrow corridor becomes a line. Lines along roads from the map
+// data only fill in gaps, and roads from the map data that run beside a corridor are marked.
+
+const GROUND_CLASS: u8 = 2u8;
+const CELL_SIZE: f64 = 1f64;
+const MAX_ROUGHNESS: f64 = 0.05f64;
+// Intensity relative to the mean intensity within this radius.
+const BACKGROUND_RADIUS: usize = 10;
+const MIN_INTENSITY_CONTRAST: f64 = 0.15f64;
+
+const MIN_CELLS_IN_CORRIDOR: usize = 30;
+const MAX_CORRIDOR_WIDTH: f64 = 8f64;
+const MIN_ELONGATION: f64 = 5f64;
+// Lengths at 1:15000.
+const MIN_ROAD_LENGTH: f64 = 30f64;
+const SIMPLIFICATION_TOLERANCE: f64 = 2f64;
+
+const MIN_TRACK_WIDTH: f64 = 2.5f64;
+// The symbols the OSM importer uses for highway=track and highway=path.
+const TRACK: i32 = 505000;
+const PATH: i32 = 506000;
+
+// Corridors within this distance of a road from the map data follow it. If they are on
+// average further away than the misalignment distance, the road is marked.
+const MATCH_DISTANCE: f64 = 10f64;
+const MISALIGNMENT_DISTANCE: f64 = 4f64;
+const MIN_MATCHED_FRACTION_FOR_MISALIGNMENT: f64 = 0.5f64;
+
+fn ground_intensity(records: &Vec<PointDataRecord>, point_converter: &PointConverter, grid: &Raster) -> Raster {
+    let mut sums = vec![0f64; grid.values.len()];
+    let mut counts = vec![0u32; grid.values.len()];
+    for record in records.iter().filter(|r| r.classification() == GROUND_CLASS) {
+        let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+        if let Some((column, row)) = grid.cell_of(p.x, p.y) {
+            let i = row*grid.columns + column;
+            sums[i] = sums[i] + record.intensity as f64;
+            counts[i] = counts[i] + 1;
+        }
+    }
+    let mut intensity = grid.empty_like();
+    for i in 0..intensity.values.len() {
+        if counts[i] > 0 {
+            intensity.values[i] = sums[i] / (counts[i] as f64);
+        }
+    }
+    intensity
+}
+
+fn length(points: &[Sweref]) -> f64 {
+    points.windows(2)
+        .map(|p| f64::sqrt((p[1].east - p[0].east)*(p[1].east - p[0].east) + (p[1].north - p[0].north)*(p[1].north - p[0].north)))
+        .sum()
+}
+
+pub fn detect_roads(records: &Vec<PointDataRecord>, point_converter: &PointConverter,
+    dtm: &DigitalTerrainModel, features: &FeatureIndex, settings: &MapSettings,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "ROADS".white();
+    let ground = Raster::from_dtm(dtm, CELL_SIZE);
+    let roughness = Raster { values: ground.minus(&ground.mean_filter(1)).values.iter().map(|v| v.abs()).collect(), ..ground.clone() }
+        .mean_filter(1);
+    let intensity = ground_intensity(records, point_converter, &ground);
+    let background = intensity.mean_filter(BACKGROUND_RADIUS);
+
+    let is_candidate: Vec<bool> = (0..ground.values.len()).map(|i| {
+        let contrast = (intensity.values[i] - background.values[i]) / background.values[i];
+        if !(roughness.values[i] < MAX_ROUGHNESS && contrast.abs() >= MIN_INTENSITY_CONTRAST) { return false }
+        let p = ground.centre(i % ground.columns, i / ground.columns);
+        let p = Sweref { east: p.x, north: p.y };
+        !MAPPED_AREAS.iter().any(|kind| features.is_inside(*kind, &p))
+    }).collect();
+
+    let mut num_corridors = 0;
+    let mut num_lines = 0;
+    let mut num_misaligned = 0;
+    for corridor in ground.connected_groups(|i| is_candidate[i]).iter() {
+        if corridor.len() < MIN_CELLS_IN_CORRIDOR { continue }
+
+        let start = *corridor.iter().next().unwrap();
+        let route: Vec<Sweref> = ground.longest_route(corridor, start).iter()
+            .map(|(c, r)| {
+                let p = ground.centre(*c, *r);
+                Sweref { east: p.x, north: p.y }
+            })
+            .collect();
+        let route_length = length(&route);
+        let width = (corridor.len() as f64) * CELL_SIZE * CELL_SIZE / route_length;
+        if route_length < settings.scaled_length(MIN_ROAD_LENGTH) || width > MAX_CORRIDOR_WIDTH || route_length < MIN_ELONGATION * width { continue }
+        num_corridors = num_corridors + 1;
+
+        let distances: Vec<Option<f64>> = route.iter().map(|p| features.distance_to(FeatureKind::Road, p, MATCH_DISTANCE)).collect();
+        let matched: Vec<f64> = distances.iter().flatten().cloned().collect();
+        if (matched.len() as f64) >= MIN_MATCHED_FRACTION_FOR_MISALIGNMENT * (route.len() as f64) &&
+            matched.iter().sum::<f64>() / (matched.len() as f64) > MISALIGNMENT_DISTANCE {
+            let marker = route[distances.iter().position(|d| d.is_some()).unwrap()];
+            post_box.send(ocad::Object::point_object(ocad::ROAD_PROBLEM, &marker, 0f64)).expect("Unable to send road marker!");
+            num_misaligned = num_misaligned + 1;
+        }
+
+        // Runs of the route away from known roads.
+        let symbol = if width >= MIN_TRACK_WIDTH { TRACK } else { PATH };
+        let runs = distances.split(|d| d.is_some()).scan(0, |start, run| {
+            let s = *start;
+            *start = s + run.len() + 1;
+            Some(&route[s..s+run.len()])
+        });
+        for run in runs {
+            if run.len() < 2 || length(run) < settings.scaled_length(MIN_ROAD_LENGTH) { continue }
+            let line = LineString::from(run.iter().map(|p| Coordinate { x: p.east, y: p.north }).collect::<Vec<_>>())
+                .simplifyvw(&settings.scaled_area(SIMPLIFICATION_TOLERANCE));
+            let segments = line.points_iter()
+                .enumerate()
+                .map(|x| {
+                    let s: Sweref = Sweref::from(&x.1);
+                    if x.0 == 0 { ocad::Segment::Move(s) } else { ocad::Segment::Line(s) }
+                }).collect();
+            post_box.send(ocad::Object {
+                object_type: ocad::ObjectType::Line(false),
+                symbol_number: symbol,
+                segments,
+                height: None,
+            }).expect("Unable to send road!");
+            num_lines = num_lines + 1;
+        }
+    }
+
+    if verbose {
+        println!("[{}] {} smooth corridors, {} tracks and paths added, {} roads from the map data may be misaligned.", &module,
+            num_corridors, num_lines, num_misaligned);
+    }
+}
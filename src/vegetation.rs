@@ -0,0 +1,340 @@
+use super::las::PointDataRecord;
+use crate::geometry::PointConverter;
+use super::dtm::{DigitalTerrainModel,Terrain};
+use super::boundary::{grow_region,Region,RegionLimits,extract_vertices,extract_segments};
+use super::feature_index::{FeatureIndex,FeatureKind,MAPPED_AREAS};
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use delaunator::EMPTY;
+use std::sync::mpsc::Sender;
+use colored::*;
+
+// Laser returns over each triangle of the DTM, and how many of them hit the canopy.
+// Detectors summarize them over the triangles of a region.
+
+const GROUND_CLASS: u8 = 2u8;
+const VEGETATION_CLASSES: [u8;3] = [3u8, 4u8, 5u8];
+const UNDERGROWTH_CLASSES: [u8;2] = [3u8, 4u8];
+// Returns higher than this above the ground are counted as canopy.
+pub const CANOPY_HEIGHT: f64 = 2f64;
+// Lower vegetation returns are grass and ground noise.
+const MIN_VEGETATION_HEIGHT: f64 = 0.5f64;
+
+pub struct CanopyStatistics {
+    returns: Vec<u32>,
+    ground: Vec<u32>,
+    vegetation: Vec<u32>,
+    undergrowth: Vec<u32>,
+    canopy: Vec<u32>,
+    canopy_height_sum: Vec<f32>,
+    top: Vec<f32>,
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct Canopy {
+    // Fraction of returns that hit the canopy.
+    pub cover: f64,
+    pub mean_height: f64,
+}
+
+impl CanopyStatistics {
+    pub fn new(records: &Vec<PointDataRecord>, point_converter: &PointConverter, dtm: &DigitalTerrainModel) -> CanopyStatistics {
+        let mut statistics = CanopyStatistics {
+            returns: vec![0; dtm.num_triangles],
+            ground: vec![0; dtm.num_triangles],
+            vegetation: vec![0; dtm.num_triangles],
+            undergrowth: vec![0; dtm.num_triangles],
+            canopy: vec![0; dtm.num_triangles],
+            canopy_height_sum: vec![0f32; dtm.num_triangles],
+            top: vec![0f32; dtm.num_triangles],
+        };
+        let mut hint = 0usize;
+        for record in records.iter() {
+            let p = point_converter.record_coordinates_to_point_3d(&[record.x, record.y, record.z]);
+            if !dtm.bounds.contains_2d(&p) { continue }
+            let t = match dtm.triangle_containing_point(&p, hint) {
+                Some(t) => t,
+                None => continue,
+            };
+            hint = t;
+            statistics.returns[t] = statistics.returns[t] + 1;
+            if record.classification() == GROUND_CLASS {
+                statistics.ground[t] = statistics.ground[t] + 1;
+            } else if VEGETATION_CLASSES.contains(&record.classification()) {
+                let height = p.z - dtm.z_coordinate_in_triangle(&p, t);
+                if height > MIN_VEGETATION_HEIGHT {
+                    statistics.vegetation[t] = statistics.vegetation[t] + 1;
+                    statistics.top[t] = f32::max(statistics.top[t], height as f32);
+                    if UNDERGROWTH_CLASSES.contains(&record.classification()) && height <= CANOPY_HEIGHT {
+                        statistics.undergrowth[t] = statistics.undergrowth[t] + 1;
+                    }
+                }
+                if height > CANOPY_HEIGHT {
+                    statistics.canopy[t] = statistics.canopy[t] + 1;
+                    statistics.canopy_height_sum[t] = statistics.canopy_height_sum[t] + height as f32;
+                }
+            }
+        }
+        statistics
+    }
+
+    pub fn summary(&self, triangles: &[usize]) -> Canopy {
+        let returns: u32 = triangles.iter().map(|t| self.returns[*t]).sum();
+        let canopy: u32 = triangles.iter().map(|t| self.canopy[*t]).sum();
+        let height_sum: f64 = triangles.iter().map(|t| self.canopy_height_sum[*t] as f64).sum();
+        Canopy {
+            cover: if returns > 0 { (canopy as f64) / (returns as f64) } else { 0f64 },
+            mean_height: if canopy > 0 { height_sum / (canopy as f64) } else { 0f64 },
+        }
+    }
+
+    // Height of the highest vegetation return over the triangle.
+    pub fn top_height(&self, triangle: usize) -> f64 {
+        self.top[triangle] as f64
+    }
+
+    // Structure of the vegetation over each triangle. Counts are averaged with the
+    // neighbouring triangles once per pass, since most triangles hold only a few returns.
+    pub fn smoothed_structure(&self, dtm: &DigitalTerrainModel, passes: usize) -> Vec<Structure> {
+        let mut counts: Vec<[f64;6]> = (0..dtm.num_triangles).map(|t| [
+            self.returns[t] as f64, self.ground[t] as f64, self.vegetation[t] as f64,
+            self.canopy[t] as f64, self.top[t] as f64, self.undergrowth[t] as f64,
+        ]).collect();
+        for _ in 0..passes {
+            counts = (0..dtm.num_triangles).map(|t| {
+                let mut sum = counts[t];
+                let mut n = 1f64;
+                for o in neighbours(dtm, t) {
+                    for i in 0..6 {
+                        sum[i] = sum[i] + counts[o][i];
+                    }
+                    n = n + 1f64;
+                }
+                let mut average = [0f64; 6];
+                for i in 0..6 {
+                    average[i] = sum[i] / n;
+                }
+                average
+            }).collect();
+        }
+        counts.iter().map(|c| {
+            let returns = c[0];
+            // Returns that made it through the canopy end up on either the ground or the undergrowth.
+            let below_canopy = c[1] + c[5];
+            Structure {
+                top_height: c[4],
+                density: if returns > 0f64 { c[2] / returns } else { 0f64 },
+                penetration: if returns > 0f64 { c[1] / returns } else { 1f64 },
+                cover: if returns > 0f64 { c[3] / returns } else { 0f64 },
+                undergrowth: if below_canopy > 0f64 { c[5] / below_canopy } else { 0f64 },
+            }
+        }).collect()
+    }
+}
+
+#[derive(Clone,Copy,Debug)]
+pub struct Structure {
+    // Height of the highest vegetation return.
+    pub top_height: f64,
+    // Fractions of the returns that hit vegetation, the ground and the canopy.
+    pub density: f64,
+    pub penetration: f64,
+    pub cover: f64,
+    // Fraction of the returns below the canopy that hit low and medium vegetation.
+    pub undergrowth: f64,
+}
+
+fn neighbours(dtm: &DigitalTerrainModel, t: usize) -> impl Iterator<Item=usize> + '_ {
+    (t*3..t*3+3).map(move |h| dtm.opposite(h)).filter(|o| *o != EMPTY).map(|o| o / 3)
+}
+
+// Vegetation classes, from the top height and density of the smoothed returns. Greens are
+// young or dense forest where few returns reach the ground. Under tall forest, dense low and
+// medium vegetation is undergrowth instead. Forest is left white.
+
+const SMOOTHING_PASSES: usize = 3;
+
+const MAX_TOP_HEIGHT_FOR_OPEN: f64 = 1f64;
+const MAX_DENSITY_FOR_OPEN: f64 = 0.1f64;
+const MAX_COVER_FOR_ROUGH_OPEN: f64 = 0.1f64;
+const MAX_TOP_HEIGHT_FOR_GREEN: f64 = 8f64;
+const MAX_PENETRATION_FOR_SLOW: f64 = 0.3f64;
+const MAX_PENETRATION_FOR_DIFFICULT: f64 = 0.15f64;
+const MAX_PENETRATION_FOR_VERY_DIFFICULT: f64 = 0.05f64;
+const MIN_COVER_FOR_UNDERGROWTH: f64 = 0.3f64;
+const MIN_UNDERGROWTH_FOR_SLOW: f64 = 0.3f64;
+const MIN_UNDERGROWTH_FOR_DIFFICULT: f64 = 0.5f64;
+
+// Areas at 1:15000.
+const MIN_VEGETATION_AREA: f64 = 300f64;
+const MIN_UNDERGROWTH_AREA: f64 = 600f64;
+
+// Open land ends in a distinct vegetation boundary where the canopy rises this sharply across
+// the edge. The structure is only smoothed once for this, to keep the edge sharp.
+const EDGE_SMOOTHING_PASSES: usize = 1;
+const MIN_TOP_HEIGHT_STEP: f64 = 4f64;
+const MIN_COVER_STEP: f64 = 0.3f64;
+const MIN_BOUNDARY_LENGTH: f64 = 20f64;
+// Boundaries mostly along a cultivation boundary from the map data are left out.
+const CULTIVATION_BOUNDARY_DISTANCE: f64 = 5f64;
+const MAX_FRACTION_NEAR_CULTIVATION_BOUNDARY: f64 = 0.5f64;
+const DISTINCT_BOUNDARY: i32 = 416000;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Vegetation {
+    Open,
+    RoughOpen,
+    SlowRunning,
+    DifficultToRun,
+    VeryDifficultToRun,
+    SlowUndergrowth,
+    DifficultUndergrowth,
+    Forest,
+}
+
+impl Vegetation {
+    fn symbol(&self) -> i32 { match self {
+        Self::Open => 401000,
+        Self::RoughOpen => 403000,
+        Self::SlowRunning => 406000,
+        Self::DifficultToRun => 408000,
+        Self::VeryDifficultToRun => 410000,
+        Self::SlowUndergrowth => 407000,
+        Self::DifficultUndergrowth => 409000,
+        Self::Forest => 405000,
+    }}
+
+    fn is_open(&self) -> bool {
+        *self == Vegetation::Open || *self == Vegetation::RoughOpen
+    }
+
+    fn min_area(&self) -> f64 { match self {
+        Self::SlowUndergrowth | Self::DifficultUndergrowth => MIN_UNDERGROWTH_AREA,
+        _ => MIN_VEGETATION_AREA,
+    }}
+
+    fn classify(structure: &Structure) -> Vegetation {
+        if structure.top_height < MAX_TOP_HEIGHT_FOR_OPEN && structure.density < MAX_DENSITY_FOR_OPEN {
+            Vegetation::Open
+        } else if structure.cover < MAX_COVER_FOR_ROUGH_OPEN {
+            Vegetation::RoughOpen
+        } else if structure.top_height > MAX_TOP_HEIGHT_FOR_GREEN {
+            if structure.cover < MIN_COVER_FOR_UNDERGROWTH {
+                Vegetation::Forest
+            } else if structure.undergrowth >= MIN_UNDERGROWTH_FOR_DIFFICULT {
+                Vegetation::DifficultUndergrowth
+            } else if structure.undergrowth >= MIN_UNDERGROWTH_FOR_SLOW {
+                Vegetation::SlowUndergrowth
+            } else {
+                Vegetation::Forest
+            }
+        } else if structure.penetration < MAX_PENETRATION_FOR_VERY_DIFFICULT {
+            Vegetation::VeryDifficultToRun
+        } else if structure.penetration < MAX_PENETRATION_FOR_DIFFICULT {
+            Vegetation::DifficultToRun
+        } else if structure.penetration < MAX_PENETRATION_FOR_SLOW {
+            Vegetation::SlowRunning
+        } else {
+            Vegetation::Forest
+        }
+    }
+}
+
+fn length(line: &Vec<Sweref>) -> f64 {
+    line.windows(2)
+        .map(|p| f64::sqrt((p[1].east - p[0].east)*(p[1].east - p[0].east) + (p[1].north - p[0].north)*(p[1].north - p[0].north)))
+        .sum()
+}
+
+// Parts of the edge of an open region where the forest outside is clearly taller or denser.
+fn distinct_boundaries(dtm: &DigitalTerrainModel, region: &Region, structure: &Vec<Structure>,
+    classes: &Vec<Vegetation>, features: &FeatureIndex, settings: &MapSettings) -> Vec<Vec<Sweref>> {
+
+    let is_sharp = |h: usize| {
+        let o = dtm.opposite(h);
+        if o == EMPTY { return false }
+        let (inside, outside) = if region.contains(h / 3) { (h / 3, o / 3) } else { (o / 3, h / 3) };
+        if dtm.exterior[outside] || classes[outside].is_open() { return false }
+        structure[outside].top_height - structure[inside].top_height >= MIN_TOP_HEIGHT_STEP ||
+            structure[outside].cover - structure[inside].cover >= MIN_COVER_STEP
+    };
+    region.loops.iter()
+        .flat_map(|l| extract_segments(dtm, l, is_sharp))
+        .filter(|line| length(line) >= settings.scaled_length(MIN_BOUNDARY_LENGTH))
+        .filter(|line| {
+            let near = line.iter()
+                .filter(|p| features.is_near(FeatureKind::CultivationBoundary, p, CULTIVATION_BOUNDARY_DISTANCE))
+                .count();
+            (near as f64) <= MAX_FRACTION_NEAR_CULTIVATION_BOUNDARY * (line.len() as f64)
+        })
+        .collect()
+}
+
+pub fn map_vegetation(dtm: &DigitalTerrainModel, statistics: &CanopyStatistics, features: &FeatureIndex,
+    settings: &MapSettings, post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "VEGETATION".green();
+    let classes: Vec<Vegetation> = statistics.smoothed_structure(dtm, SMOOTHING_PASSES).iter()
+        .enumerate()
+        .map(|(t, s)| {
+            let p = dtm.triangle_incenter(t);
+            let p = Sweref { east: p.x, north: p.y };
+            if dtm.exterior[t] || dtm.terrain[t] == Terrain::Lake ||
+                MAPPED_AREAS.iter().any(|kind| features.is_inside(*kind, &p)) {
+                Vegetation::Forest
+            } else {
+                Vegetation::classify(s)
+            }
+        })
+        .collect();
+    let edge_structure = statistics.smoothed_structure(dtm, EDGE_SMOOTHING_PASSES);
+
+    let mut visited = vec![false; dtm.num_triangles];
+    let mut num_boundaries = 0;
+    let mut counts = [0usize; 8];
+    let mut areas = [0f64; 8];
+    for seed in 0..dtm.num_triangles {
+        let vegetation = classes[seed];
+        if visited[seed] || vegetation == Vegetation::Forest { continue }
+
+        let limits = RegionLimits {
+            min_area: settings.scaled_area(vegetation.min_area()),
+            ..RegionLimits::none()
+        };
+        let region = grow_region(dtm, seed, &limits, |_, halfedge| {
+            let t = halfedge / 3;
+            !visited[t] && classes[t] == vegetation
+        });
+        for t in region.triangles.iter() {
+            visited[*t] = true;
+        }
+        if !limits.accepts(&region) { continue }
+
+        let (outer, islands) = region.outer_edge_and_islands(dtm);
+        ocad::post_objects_without_clipping(
+            extract_vertices(dtm, &outer, &islands),
+            &vec![ocad::GraphSymbol::Fill(vegetation.symbol())],
+            post_box);
+        if vegetation.is_open() {
+            let boundaries = distinct_boundaries(dtm, &region, &edge_structure, &classes, features, settings);
+            num_boundaries = num_boundaries + boundaries.len();
+            ocad::post_objects_without_clipping(
+                boundaries,
+                &vec![ocad::GraphSymbol::Stroke(DISTINCT_BOUNDARY, false)],
+                post_box);
+        }
+        counts[vegetation as usize] = counts[vegetation as usize] + 1;
+        areas[vegetation as usize] = areas[vegetation as usize] + region.area;
+    }
+
+    if verbose {
+        for vegetation in [Vegetation::Open, Vegetation::RoughOpen, Vegetation::SlowRunning,
+            Vegetation::DifficultToRun, Vegetation::VeryDifficultToRun,
+            Vegetation::SlowUndergrowth, Vegetation::DifficultUndergrowth].iter() {
+            println!("[{}] {} areas of {}, total area {:.0} m².", &module,
+                counts[*vegetation as usize], vegetation.symbol(), areas[*vegetation as usize]);
+        }
+        println!("[{}] {} distinct vegetation boundaries along open land.", &module, num_boundaries);
+    }
+}
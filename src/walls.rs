@@ -0,0 +1,113 @@
+use super::relief::LocalRelief;
+use super::feature_index::{FeatureIndex,FeatureKind};
+use super::map_settings::MapSettings;
+use super::ocad;
+use super::Sweref;
+use std::sync::mpsc::Sender;
+use colored::*;
+
+// Walls are narrow ridges in the local relief model. Each cell is compared with the ground a
+// little to each side, across four directions. Crest cells are joined into lines. Walls with
+// steep sides are stone walls, and rounded ones earth walls. Lower ridges are found in a
+// second pass, apart from the walls, and drawn as small earth walls.
+
+const MIN_WALL_HEIGHT: f64 = 0.5f64;
+const MIN_SMALL_WALL_HEIGHT: f64 = 0.3f64;
+const MAX_WALL_HEIGHT: f64 = 1.5f64;
+// A wall is at most 2 m wide, so the ground 2 m from the crest is at its foot.
+const FOOT_DISTANCE: f64 = 2f64;
+const SIDE_DISTANCE: f64 = 1f64;
+// Both sides must drop, or it is a terrace.
+const MIN_SIDE_DROP: f64 = 0.25f64;
+// Fraction of the height that the ground has dropped half way to the foot, for a stone wall.
+const MIN_STEEPNESS_FOR_STONE: f64 = 0.5f64;
+const MIN_STEEP_FRACTION: f64 = 0.5f64;
+const MIN_HEIGHT_FOR_INTACT: f64 = 0.8f64;
+
+const MAX_CREST_WIDTH: f64 = 3f64;
+// Lengths at 1:15000.
+const MIN_WALL_LENGTH: f64 = 20f64;
+const SIMPLIFICATION_TOLERANCE: f64 = 1f64;
+
+// Embankments along roads are not walls.
+const ROAD_DISTANCE: f64 = 5f64;
+
+const STONE_WALL: i32 = 513000;
+const RUINED_STONE_WALL: i32 = 514000;
+const EARTH_WALL: i32 = 105000;
+const SMALL_EARTH_WALL: i32 = 106000;
+
+// Height of the crest over the ground on both sides, and whether the sides are steep.
+fn crest(relief: &LocalRelief, column: usize, row: usize, min_height: f64) -> Option<(f64,bool)> {
+    let ground = &relief.ground;
+    let p = ground.centre(column, row);
+    if p.z.is_nan() || relief.relief.get(column, row) <= 0f64 { return None }
+    (0..4).filter_map(|k| {
+        let angle = (k as f64) * std::f64::consts::FRAC_PI_4;
+        let (ux, uy) = (f64::cos(angle), f64::sin(angle));
+        let at = |d: f64| ground.value_at(p.x + ux*d, p.y + uy*d);
+        let (d1, d2) = (p.z - at(-FOOT_DISTANCE), p.z - at(FOOT_DISTANCE));
+        let (s1, s2) = (p.z - at(-SIDE_DISTANCE), p.z - at(SIDE_DISTANCE));
+        let height = 0.5f64 * (d1 + d2);
+        if !(f64::min(d1, d2) >= MIN_SIDE_DROP && s1 >= 0f64 && s2 >= 0f64 &&
+            height >= min_height && height <= MAX_WALL_HEIGHT) { return None }
+        Some((height, f64::min(s1, s2) >= MIN_STEEPNESS_FOR_STONE * height))
+    })
+    .fold(None, |best: Option<(f64,bool)>, c| match best {
+        Some(b) if b.0 >= c.0 => best,
+        _ => Some(c),
+    })
+}
+
+pub fn detect_walls(relief: &LocalRelief, features: &FeatureIndex, settings: &MapSettings,
+    post_box: &Sender<ocad::Object>, verbose: bool) {
+
+    let module = "WALLS".yellow();
+    let ground = &relief.ground;
+    let crests: Vec<Option<(f64,bool)>> = (0..ground.values.len())
+        .map(|i| crest(relief, i % ground.columns, i / ground.columns, MIN_WALL_HEIGHT))
+        .collect();
+
+    let mut counts = [0usize; 4];
+    for wall in relief.linear_features(|i| crests[i].is_some(), MIN_WALL_LENGTH, MAX_CREST_WIDTH, settings).iter() {
+        let near_road = wall.route.0.iter()
+            .filter(|c| features.is_near(FeatureKind::Road, &Sweref { east: c.x, north: c.y }, ROAD_DISTANCE))
+            .count();
+        if near_road * 2 > wall.route.0.len() { continue }
+
+        let values: Vec<(f64,bool)> = wall.cells.iter().map(|(c, r)| crests[r*ground.columns + c].unwrap()).collect();
+        let n = values.len() as f64;
+        let height = values.iter().map(|v| v.0).sum::<f64>() / n;
+        let steep = (values.iter().filter(|v| v.1).count() as f64) / n;
+        let (kind, symbol) = if steep < MIN_STEEP_FRACTION {
+            (2, EARTH_WALL)
+        } else if height >= MIN_HEIGHT_FOR_INTACT {
+            (0, STONE_WALL)
+        } else {
+            (1, RUINED_STONE_WALL)
+        };
+        post_box.send(wall.object(symbol, height, SIMPLIFICATION_TOLERANCE, settings)).expect("Unable to send wall!");
+        counts[kind] = counts[kind] + 1;
+    }
+
+    // Low crests that are not part of a wall. Groups that touch a wall crest are its ends.
+    let low_crests: Vec<Option<(f64,bool)>> = (0..ground.values.len())
+        .map(|i| if crests[i].is_some() { None } else { crest(relief, i % ground.columns, i / ground.columns, MIN_SMALL_WALL_HEIGHT) })
+        .collect();
+    for wall in relief.linear_features(|i| low_crests[i].is_some(), MIN_WALL_LENGTH, MAX_CREST_WIDTH, settings).iter() {
+        if wall.cells.iter().any(|cell| ground.neighbours(*cell).any(|(c, r)| crests[r*ground.columns + c].is_some())) { continue }
+        let near_road = wall.route.0.iter()
+            .filter(|c| features.is_near(FeatureKind::Road, &Sweref { east: c.x, north: c.y }, ROAD_DISTANCE))
+            .count();
+        if near_road * 2 > wall.route.0.len() { continue }
+
+        let height = wall.cells.iter().map(|(c, r)| low_crests[r*ground.columns + c].unwrap().0).sum::<f64>() / (wall.cells.len() as f64);
+        post_box.send(wall.object(SMALL_EARTH_WALL, height, SIMPLIFICATION_TOLERANCE, settings)).expect("Unable to send wall!");
+        counts[3] = counts[3] + 1;
+    }
+
+    if verbose {
+        println!("[{}] {} stone walls, {} ruined stone walls, {} earth walls and {} small earth walls.", &module,
+            counts[0], counts[1], counts[2], counts[3]);
+    }
+}
@@ -1,331 +1,282 @@
-use super::dtm::{DigitalTerrainModel,Terrain,Halfedge,TriangleWalk};
-use crate::geometry::Point3D;
+use super::dtm::{DigitalTerrainModel,Terrain};
+use super::feature_index::{FeatureIndex,FeatureKind};
+use super::map_settings::MapSettings;
 use delaunator::EMPTY;
-use super::boundary::{Boundary,extract_vertices};
 use std::f64;
 use super::ocad;
 use std::sync::mpsc::Sender;
+use std::collections::BinaryHeap;
+use std::cmp::Ordering;
 use colored::*;
 use super::Sweref;
-use std::cmp::Ordering;
-
-const ABSORBTION_FACTOR: f64 = 0.2f64;
-const NOT_ABSORBED: f64 = 1f64 - ABSORBTION_FACTOR;
-const RAIN_MM: f64 = 10f64;
-const RAIN_M: f64 = RAIN_MM*0.001;
-const ITERATE_UNTIL_ONLY_THIS_MUCH_WATER_REMAINS: f64 = 5f64; // cubic m
-
-const FLAT_TRIANGLE: f64 = 0.993f64;
-const DROPOFF: f64 = 50f64;
-
-
-// The overlap is intentional.
-const DIFFUSE_MARSH_LOWER_LIMIT: f64 = RAIN_M*8.0;
-const DIFFUSE_MARSH_UPPER_LIMIT: f64 = RAIN_M*16.0;
-
-const NORMAL_MARSH_LOWER_LIMIT: f64 = RAIN_M*12.0;
-const NORMAL_MARSH_UPPER_LIMIT: f64 = RAIN_M*17.0;
-
-const IMPASSABLE_MARSH_LOWER_LIMIT: f64 = RAIN_M*12.0;
-
-const MIN_AREA_FOR_SEED: f64 = 0.5f64;
-
-#[derive(Debug)]
-enum MarshType {
-    Diffuse,
-    Normal,
-    Impassable,
+use ::geo::{Coordinate,LineString};
+use ::geo::algorithm::simplifyvw::SimplifyVW;
+use ::geo::algorithm::euclidean_length::EuclideanLength;
+
+// Water is routed from triangle to triangle by steepest descent. Depressions are filled
+// first, so that all water reaches the map edge or a lake. Channels form where enough area
+// drains through a triangle.
+
+// Contributing areas in m².
+const MIN_AREA_FOR_CHANNEL: f64 = 20000f64;
+const MIN_AREA_FOR_STREAM: f64 = 200000f64;
+
+// Filled triangles are raised this much above the one they drain to, so that flats drain.
+const FILL_GRADIENT: f64 = 0.0001f64;
+
+// A channel that is this straight over this length, at 1:15000, is a ditch.
+const MIN_STRAIGHTNESS_FOR_DITCH: f64 = 0.98f64;
+const MIN_LENGTH_FOR_DITCH: f64 = 30f64;
+const MIN_CHANNEL_LENGTH: f64 = 20f64;
+const SIMPLIFICATION_TOLERANCE: f64 = 2f64;
+
+// Streams need to follow an existing watercourse from the map data for this fraction of
+// their samples. Otherwise they are only drawn as minor channels.
+const CONFIRMATION_DISTANCE: f64 = 15f64;
+const MIN_CONFIRMED_FRACTION: f64 = 0.5f64;
+const SAMPLE_SPACING: f64 = 10f64;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+enum Watercourse {
+    Stream,
+    Ditch,
+    MinorChannel,
 }
 
-impl MarshType {
+impl Watercourse {
+    // Ditches get the minor water channel, as ditches from the map data do. Minor channels
+    // are drawn with a copy of it, since 307 is the uncrossable marsh in ISOM 2017.
     fn symbol(&self) -> i32 { match self {
-        // Self::Diffuse => 310000,
-        // Self::Normal => 308000,
-        // Self::Impassable => 307000,
-        Self::Diffuse => 214000,
-        Self::Normal => 406000,
-        Self::Impassable => 408000,
-    }}
-
-    fn limits(&self) -> (f64, f64) { match self {
-        Self::Diffuse => (DIFFUSE_MARSH_LOWER_LIMIT, DIFFUSE_MARSH_UPPER_LIMIT),
-        Self::Normal => (NORMAL_MARSH_LOWER_LIMIT, NORMAL_MARSH_UPPER_LIMIT),
-        Self::Impassable => (IMPASSABLE_MARSH_LOWER_LIMIT, f64::MAX),
+        Self::Stream => 305000,
+        Self::Ditch => 306000,
+        Self::MinorChannel => ocad::MINOR_CHANNEL,
     }}
 }
 
-struct Marsh<'a> {
-    halfedges: Vec<Halfedge>,
-    index: usize,
-    dtm: &'a DigitalTerrainModel,
-    indices_for_each_triangle: &'a mut Vec<usize>,
-
-    min_z_of_wet_triangles: f64,
-    max_z_of_wet_triangles: f64,
-
-    water_lower_limit: f64,
-    water_upper_limit: f64,
+// Min-heap entry for the priority flood.
+pub struct Flooded(pub f64, pub usize);
 
-    z_limits: &'a Vec<(f64,f64)>,
-
-    absorbed_water: &'a Vec<f64>,
+impl PartialEq for Flooded {
+    fn eq(&self, other: &Self) -> bool { self.0 == other.0 }
 }
-
-impl<'a> Marsh<'a> {
-    fn absorbed_water_in_range(&self, t: usize) -> bool {
-        self.absorbed_water[t] >= self.water_lower_limit && self.absorbed_water[t] <= self.water_upper_limit
+impl Eq for Flooded {}
+impl PartialOrd for Flooded {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Flooded {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.partial_cmp(&self.0).unwrap_or(Ordering::Equal)
     }
 }
 
-impl<'a> Boundary for Marsh<'a> {
-    fn claim(&mut self, triangle: usize) { self.indices_for_each_triangle[triangle] = self.index; }
-    
-    fn push_halfedge(&mut self, h: Halfedge) { 
-        let t = h / 3;
-        if self.absorbed_water_in_range(t) {
-            let z = self.z_limits[t];
-            if z.0 < self.min_z_of_wet_triangles { self.min_z_of_wet_triangles = z.0 }
-            if z.1 > self.max_z_of_wet_triangles { self.max_z_of_wet_triangles = z.1 }
+fn is_outlet(dtm: &DigitalTerrainModel, t: usize) -> bool {
+    dtm.exterior[t] || dtm.terrain[t] == Terrain::Lake
+}
+
+fn neighbours(dtm: &DigitalTerrainModel, t: usize) -> impl Iterator<Item=usize> + '_ {
+    (t*3..t*3+3).map(move |h| dtm.opposite(h)).filter(|o| *o != EMPTY).map(|o| o / 3)
+}
 
+// Filled elevation of each triangle, and the triangle it drains to. Outlets drain nowhere.
+fn route_water(dtm: &DigitalTerrainModel) -> (Vec<f64>, Vec<usize>) {
+    let incenters: Vec<_> = (0..dtm.num_triangles).map(|t| dtm.triangle_incenter(t)).collect();
+    let mut filled: Vec<f64> = incenters.iter().map(|p| p.z).collect();
+    let mut done = vec![false; dtm.num_triangles];
+    let mut heap = BinaryHeap::new();
+
+    // Priority flood from the outlets, raising every triangle to at least the level of the
+    // triangle it was reached from.
+    for t in 0..dtm.num_triangles {
+        if is_outlet(dtm, t) {
+            done[t] = true;
+            heap.push(Flooded(filled[t], t));
         }
-
-//        println!("{} water, {} halfedges, {} to {}", self.absorbed_water[t], self.halfedges.len(), self.min_z_of_wet_triangles, self.max_z_of_wet_triangles);
-        self.halfedges.push(h); 
     }
-
-    fn dtm(&self) -> &DigitalTerrainModel { self.dtm }
-    fn get_halfedges(&self) -> &Vec<Halfedge> { &self.halfedges }
-
-    fn should_recurse(&self, halfedge: Halfedge) -> bool {
-        let t = halfedge / 3;
-        self.indices_for_each_triangle[t] == 0 &&
-        self.dtm.terrain[t] == Terrain::Unclassified &&
-        !self.dtm.exterior[t] &&
-        (self.absorbed_water_in_range(t) || 
-        (self.z_limits[t].0 > self.min_z_of_wet_triangles && self.z_limits[t].0 < self.max_z_of_wet_triangles &&
-            self.z_limits[t].1 > self.min_z_of_wet_triangles && self.z_limits[t].1 < self.max_z_of_wet_triangles ))
+    while let Some(Flooded(z, t)) = heap.pop() {
+        for n in neighbours(dtm, t) {
+            if done[n] { continue }
+            done[n] = true;
+            filled[n] = f64::max(filled[n], z + FILL_GRADIENT);
+            heap.push(Flooded(filled[n], n));
+        }
     }
 
-    // fn grow_from_seed(&mut self, triangle: usize) {
-    //     // Growing needs to be breadth-first for marshes, since they can 
-    // }
+    // Steepest descent on the filled surface.
+    let receivers = (0..dtm.num_triangles).map(|t| {
+        if is_outlet(dtm, t) { return EMPTY }
+        neighbours(dtm, t)
+            .filter(|n| filled[*n] < filled[t])
+            .map(|n| (n, (filled[t] - filled[n]) / incenters[t].distance_2d_to(&incenters[n])))
+            .fold(None, |best: Option<(usize,f64)>, (n, slope)| match best {
+                Some((_, s)) if s >= slope => best,
+                _ => Some((n, slope)),
+            })
+            .map(|(n, _)| n)
+            .unwrap_or(EMPTY)
+    }).collect();
+    (filled, receivers)
 }
 
-fn area_of_triangle_from_three_points(p0: &Point3D, p1: &Point3D, p2: &Point3D) -> f64 {
-    f64::abs(0.5 * (p0.x * (p1.y - p2.y) +
-                    p1.x * (p2.y - p0.y) +
-                    p2.x * (p0.y - p1.y)))
+fn accumulate(dtm: &DigitalTerrainModel, filled: &Vec<f64>, receivers: &Vec<usize>) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..dtm.num_triangles).collect();
+    order.sort_by(|a,b| filled[*b].partial_cmp(&filled[*a]).unwrap_or(Ordering::Equal));
+    let mut accumulated = dtm.areas.clone();
+    for t in order.into_iter() {
+        let r = receivers[t];
+        if r != EMPTY {
+            accumulated[r] = accumulated[r] + accumulated[t];
+        }
+    }
+    accumulated
 }
 
-// In Lantmäteriet data, the overlap region between two flight paths offer a large number of small completely flat
-// triangles, owing to the higher point density in these areas. We must ensure that water keeps flowing past these triangles.
-
-pub fn rain_on(dtm: &mut DigitalTerrainModel,             
-                post_box: &Sender<ocad::Object>,
-                verbose: bool) {
-
-    let module = "RAIN".blue();
-
-    if verbose {
-        println!("[{}] Applying {} mm of rain to entire map.", &module, RAIN_MM);
+// Splits the channel network into lines between heads, confluences and outlets.
+fn channel_lines(dtm: &DigitalTerrainModel, receivers: &Vec<usize>, accumulated: &Vec<f64>) -> Vec<Vec<usize>> {
+    let is_channel: Vec<bool> = accumulated.iter().map(|a| *a >= MIN_AREA_FOR_CHANNEL).collect();
+    let mut inflows = vec![0usize; dtm.num_triangles];
+    for t in 0..dtm.num_triangles {
+        if is_channel[t] && receivers[t] != EMPTY {
+            inflows[receivers[t]] = inflows[receivers[t]] + 1;
+        }
     }
-    
-    let mut water_per_triangle: Vec<f64> = dtm.areas.iter().map(|a| a*RAIN_M).collect();
-    let mut absorbed_water = vec![0f64;dtm.num_triangles];
-    let gravity: [f64;3] = [0f64, 0f64, -1f64];
-
-    let ratios: Vec<[f64;4]> = dtm.normals().iter().enumerate()
-        .map(|(t,n)| -> [f64;4] {
-            let p = [dtm.points[dtm.vertices[t*3+0]], dtm.points[dtm.vertices[t*3+1]], dtm.points[dtm.vertices[t*3+2]]];
 
-            if p[0].z == p[1].z && p[0].z == p[2].z {
-                return [0f64,0f64,0f64, ABSORBTION_FACTOR];
+    (0..dtm.num_triangles)
+        .filter(|t| is_channel[*t] && !is_outlet(dtm, *t) && inflows[*t] != 1)
+        .map(|start| {
+            let mut line = vec![start];
+            let mut t = start;
+            while receivers[t] != EMPTY {
+                t = receivers[t];
+                line.push(t);
+                if inflows[t] != 1 || is_outlet(dtm, t) { break }
             }
-
-            let incenter = {
-                let a = p[0].distance_3d_to(&p[1]);
-                let b = p[1].distance_3d_to(&p[2]);
-                let c = p[2].distance_3d_to(&p[0]);
-                let s = a + b + c;
-                    
-                Point3D {
-                    x: (a * p[0].x + b * p[1].x + c * p[2].x)/s,
-                    y: (a * p[0].y + b * p[1].y + c * p[2].y)/s,
-                    z: (a * p[0].z + b * p[1].z + c * p[2].z)/s,
-                }
-            };
-
-            // Calculate gravity vector in the triangle plane. For a flat triangle this will be zero length.
-            let d = gravity[0]*n[0] + gravity[1]*n[1] + gravity[2]*n[2];
-            let g = Point3D { x: gravity[0] - n[0]*d,
-                            y: gravity[1] - n[1]*d,
-                            z: gravity[2] - n[2]*d, }.normalized();
-
-            // Find which corner this vector points to.
-            let most_relevant_corner: usize = p.iter()
-                .map(|p: &Point3D| -> f64 {
-                    g.dot(&(*p - incenter).normalized())
-                })
-                .enumerate()
-                .min_by(|(_,d0), (_,d1)| if d0 < d1 { Ordering::Less } else { Ordering::Greater })
-                .unwrap().0;
-            
-            // There is a line from the relevant corner in the direction of
-            // -gravity_in_triangle_plane. We want to find its intersection with
-            // the opposite triangle edge.
-
-            let p0 = p[most_relevant_corner.next()];
-            let p1 = p[most_relevant_corner.prev()];
-            let pa = p[most_relevant_corner];
-
-            // pa.x - g.x * v = (1-t)*p0.x + t*p1.x
-            // pa.y - g.y * v = (1-t)*p0.y + t*p1.y
-            // Solve for t.
-            // (pa.x - (1-t)*p0.x - t*p1.x)*g.y = (pa.y - (1-t)*p0.y - t*p1.y) * g.x
-            // pa.x * g.y - pa.y * g.x = p0.x * g.y + t*(p1.x-p0.x)*g.y - p0.y * g.x - t*(p1.y - p0.y)*g.x
-            // (pa.x - p0.x)*g.y - (pa.y - p0.y)*g.x = t * ((p1.x - p0.x)*g.y - (p1.y - p0.y)*g.x)
-            // t = ((pa.x - p0.x)*g.y - (pa.y - p0.y)*g.x) / ((p1.x - p0.x)*g.y - (p1.y - p0.y)*g.x)
-            let t = ((pa.x - p0.x)*g.y - (pa.y - p0.y)*g.x) / ((p1.x - p0.x)*g.y - (p1.y - p0.y)*g.x);
-            let mut out = [0f64;4];
-            // Many triangles are mostly flat, we want the water to roll easily off all of them except the
-            // most flat ones. 
-            let scale = 1f64;//0.5f64*(f64::tanh(DROPOFF * (FLAT_TRIANGLE - n.z)) + 1f64);
-            out[3] = n[2] * ABSORBTION_FACTOR;
-
-            if t <= 0f64 {
-                // All water goes into the edge to the right of the corner.
-                out[most_relevant_corner.prev()] = scale*NOT_ABSORBED;
-            } else if t >= 1f64 {
-                // All water goes into the edge to the left of the corner.
-                out[most_relevant_corner] = scale*NOT_ABSORBED;
-            } else {
-                let intersect_point = Point3D { 
-                    x: (1f64 - t)*p0.x + t*p1.x, y: (1f64 - t)*p0.y + t*p1.y, z: 0f64,
-                };
-                let left = area_of_triangle_from_three_points(&pa, &p0, &intersect_point);
-                let right = area_of_triangle_from_three_points(&pa, &intersect_point, &p1);
-                out[most_relevant_corner] = left / (left + right) * scale * NOT_ABSORBED;
-                out[most_relevant_corner.prev()] = right / (left + right) * scale * NOT_ABSORBED;
-            }
-
-            out
+            line
         })
-        .collect();
-
-    for triangle in 0..dtm.num_triangles {
-        if dtm.terrain[triangle] == Terrain::Lake { water_per_triangle[triangle] = 0f64; }
-    }
-   
-    let z_lim = dtm.z_limits();
-
-    let mut iterations = 0;
-    while water_per_triangle.iter().sum::<f64>() > ITERATE_UNTIL_ONLY_THIS_MUCH_WATER_REMAINS {
-        // For each triangle, calculate the flow to neighbouring triangles.
-//        println!("{} {}", water_per_triangle[1045], water_per_triangle[45]);
-        let mut flow = vec![0f64;dtm.num_triangles];
-
-        for (triangle, water) in water_per_triangle.iter().enumerate() {
-            if dtm.terrain[triangle] == Terrain::Lake { continue }
-            let r = ratios[triangle];
-//            let absorbtion = 1f64 - r[0] - r[1] - r[2];
-
-            for i in 0..3 {
-                let outflow = r[i] * water;
-                flow[triangle] = flow[triangle] - outflow;
-                let o = dtm.opposite(triangle*3 + i);
-                let other_triangle = o/3;
-                if o != EMPTY && dtm.terrain[other_triangle] != Terrain::Lake {
-                    flow[other_triangle] = flow[other_triangle] + outflow;
-                }
-            }
-            let absorbed = r[3] * water;
-            // let absorbed = absorbtion * water;
-            absorbed_water[triangle] = absorbed_water[triangle] + absorbed;
-            flow[triangle] = flow[triangle] - absorbed;
-        }
-        println!("{} {} {}", iterations, water_per_triangle.iter().sum::<f64>(),
-    flow.iter().sum::<f64>());
-
-        //water_per_triangle = water_per_triangle.into_iter().zip(flow.into_iter()).map(|a| f64::max(a.0 + a.1,0f64)).collect();
-
-        for (triangle, delta_water) in flow.iter().enumerate() {
-            water_per_triangle[triangle] = water_per_triangle[triangle] + delta_water;
-        }
+        .collect()
+}
 
-        iterations = iterations + 1;
-    }
+pub fn find_watercourses(dtm: &DigitalTerrainModel,
+    features: &FeatureIndex,
+    settings: &MapSettings,
+    post_box: &Sender<ocad::Object>,
+    verbose: bool) {
 
+    let module = "WATER".blue();
+    let (filled, receivers) = route_water(dtm);
+    let accumulated = accumulate(dtm, &filled, &receivers);
+    let lines = channel_lines(dtm, &receivers, &accumulated);
     if verbose {
-        println!("[{}] The water has dissipated after {} iterations.", &module, iterations);
+        println!("[{}] {} channel segments with more than {:.0} m² draining through them.", &module, lines.len(), MIN_AREA_FOR_CHANNEL);
     }
 
-    let absorbed_per_sqm: Vec<f64> = absorbed_water.into_iter()
-        .zip(dtm.areas.iter())
-        .map(|(water, area)| water/area)
-        .collect();
-
-    let mut assigned_triangles = vec![0usize;dtm.num_triangles];
-
-    let mut marsh_index = 1;
-    let mut added_marshes = 0;
-
-    for (triangle, absorbed) in absorbed_per_sqm.iter().enumerate() {
-
-        if  assigned_triangles[triangle] != 0
-            || *absorbed < DIFFUSE_MARSH_LOWER_LIMIT
-            || dtm.exterior[triangle]
-            || dtm.areas[triangle] < MIN_AREA_FOR_SEED 
-            || z_lim[triangle].0 == z_lim[triangle].1
-            { continue }
-
-        let marsh_type = if *absorbed < DIFFUSE_MARSH_UPPER_LIMIT { MarshType::Diffuse } else 
-                        if *absorbed < NORMAL_MARSH_UPPER_LIMIT { MarshType::Normal } else 
-                        { MarshType::Impassable };
-        let limits = marsh_type.limits();
-
-        let mut marsh = Marsh {
-            halfedges: Vec::new(),
-            index: marsh_index,
-            dtm: dtm,
-            indices_for_each_triangle: &mut assigned_triangles,
-            z_limits: &z_lim,
-            absorbed_water: &absorbed_per_sqm,
-            
-            min_z_of_wet_triangles: f64::MAX,
-            max_z_of_wet_triangles: f64::MIN,
-
-            water_lower_limit: limits.0,
-            water_upper_limit: limits.1,
+    let mut counts = [0usize; 3];
+    let mut confirmed = 0;
+    for line in lines.into_iter() {
+        let points: Vec<Coordinate<f64>> = line.iter()
+            .map(|t| {
+                let p = dtm.triangle_incenter(*t);
+                Coordinate { x: p.x, y: p.y }
+            })
+            .collect();
+        let linestring = LineString::from(points);
+        let length = linestring.euclidean_length();
+        if length < settings.scaled_length(MIN_CHANNEL_LENGTH) { continue }
+
+        let first = linestring.0[0];
+        let last = linestring.0[linestring.0.len()-1];
+        let straightness = f64::sqrt((last.x - first.x)*(last.x - first.x) + (last.y - first.y)*(last.y - first.y)) / length;
+
+        let samples: Vec<Sweref> = linestring.0.iter()
+            .step_by(usize::max(1, (SAMPLE_SPACING * (linestring.0.len() as f64) / length) as usize))
+            .map(|c| Sweref { east: c.x, north: c.y })
+            .collect();
+        let near_existing = samples.iter()
+            .filter(|p| features.is_near(FeatureKind::Watercourse, p, CONFIRMATION_DISTANCE))
+            .count();
+        let is_confirmed = (near_existing as f64) >= MIN_CONFIRMED_FRACTION * (samples.len() as f64);
+
+        let downstream = accumulated[line[line.len()-1]];
+        let watercourse = if straightness > MIN_STRAIGHTNESS_FOR_DITCH && length > settings.scaled_length(MIN_LENGTH_FOR_DITCH) {
+            Watercourse::Ditch
+        } else if downstream >= MIN_AREA_FOR_STREAM && is_confirmed {
+            Watercourse::Stream
+        } else {
+            Watercourse::MinorChannel
         };
-
-        //marsh.grow_from_seed(triangle);
-
-        //marsh.split_into_lake_and_islands();
-        // println!("{} {} {}", triangle, absorbed, dtm.areas[triangle]);
-
-        //TODO: Remove islands that are too small, but keep the rest.
-        // if marsh.halfedges.len() > 3 {
-        let p0 = dtm.points[dtm.vertices[triangle*3]];
-        let p0_sweref = Sweref { east: p0.x, north: p0.y };
-        let pob = ocad::Object::point_object(205000, &p0_sweref, 0f64);
-        post_box.send(pob).expect("Unable to send object");
-        added_marshes = added_marshes + 1;
-        // }
-        if marsh.halfedges.len() > 3 {
-        
-            println!("{:?} {} {} {:5.2} {:?} {:?}", ratios[triangle], triangle, marsh.halfedges.len(), absorbed, marsh_type, limits.0);
-            ocad::post_objects_without_clipping(
-                extract_vertices(&dtm, &marsh.halfedges, &Vec::new()), 
-                &vec![ocad::GraphSymbol::Fill(marsh_type.symbol())],
-                &post_box);
-            added_marshes = added_marshes + 1;
+        if is_confirmed { confirmed = confirmed + 1; }
+
+        // Watercourses from the map data are already drawn. Only the gaps in them are added.
+        let is_near: Vec<bool> = linestring.0.iter()
+            .map(|c| features.is_near(FeatureKind::Watercourse, &Sweref { east: c.x, north: c.y }, CONFIRMATION_DISTANCE))
+            .collect();
+        let runs = is_near.split(|near| *near).scan(0, |start, run| {
+            let s = *start;
+            *start = s + run.len() + 1;
+            Some(&linestring.0[s..s+run.len()])
+        });
+        let mut posted = false;
+        for run in runs {
+            if run.len() < 2 { continue }
+            let run = LineString::from(run.to_vec());
+            if run.euclidean_length() < settings.scaled_length(MIN_CHANNEL_LENGTH) { continue }
+            let segments = run.simplifyvw(&settings.scaled_area(SIMPLIFICATION_TOLERANCE))
+                .points_iter()
+                .enumerate()
+                .map(|x| {
+                    let s: Sweref = Sweref::from(&x.1);
+                    if x.0 == 0 { ocad::Segment::Move(s) } else { ocad::Segment::Line(s) }
+                }).collect();
+
+            post_box.send(ocad::Object {
+                object_type: ocad::ObjectType::Line(false),
+                symbol_number: watercourse.symbol(),
+                segments,
+                height: None,
+            }).expect("Unable to send watercourse!");
+            posted = true;
         }
-        
-        marsh_index = marsh_index + 1;
+        if !posted { continue }
+
+        counts[watercourse as usize] = counts[watercourse as usize] + 1;
     }
 
     if verbose {
-        println!("[{}] {} marshes added.", &module, added_marshes);
+        println!("[{}] {} streams, {} ditches and {} minor channels added, {} channels along existing watercourses.", &module,
+            counts[Watercourse::Stream as usize], counts[Watercourse::Ditch as usize], counts[Watercourse::MinorChannel as usize], confirmed);
     }
+}
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pit_is_filled_to_its_outlet() {
+        // A bowl with its bottom in the middle of a 5 x 5 grid. Only the outer ring drains.
+        let dtm = DigitalTerrainModel::grid(5, 5, |x, y| (x - 2.5f64).powi(2) + (y - 2.5f64).powi(2));
+        let (filled, receivers) = route_water(&dtm);
+
+        let pit = 2*(2*5 + 2);
+        let lowest_outlet = (0..dtm.num_triangles)
+            .filter(|t| is_outlet(&dtm, *t))
+            .map(|t| dtm.triangle_incenter(t).z)
+            .fold(f64::MAX, f64::min);
+        assert!(filled[pit] > dtm.triangle_incenter(pit).z);
+        assert!(filled[pit] > lowest_outlet);
+
+        for t in 0..dtm.num_triangles {
+            if is_outlet(&dtm, t) {
+                assert_eq!(receivers[t], EMPTY);
+                continue
+            }
+            let mut n = t;
+            for _ in 0..dtm.num_triangles {
+                if is_outlet(&dtm, n) { break }
+                assert!(receivers[n] != EMPTY, "Triangle {} does not drain", n);
+                assert!(filled[receivers[n]] < filled[n]);
+                n = receivers[n];
+            }
+            assert!(is_outlet(&dtm, n));
+        }
+    }
+}